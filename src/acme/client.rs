@@ -0,0 +1,484 @@
+use super::jws::{external_account_binding, AccountKey};
+use crate::metrics;
+use hyper::{
+    body::{to_bytes, Bytes},
+    client::HttpConnector,
+    header::{CONTENT_TYPE, LOCATION, RETRY_AFTER},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{fmt, io, time::Duration};
+use tokio::time::sleep;
+use tokio_rustls::rustls::ClientConfig;
+
+const REPLAY_NONCE: &str = "replay-nonce";
+/// How often a request is repeated with a fresh nonce if the CA rejected the last one
+const BAD_NONCE_RETRIES: usize = 3;
+/// How often a request is repeated after a temporary failure, e.g. a 503
+const REQUEST_RETRIES: usize = 4;
+const REQUEST_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Requests the CA asks to repeat later than this are not repeated
+const MAX_REQUEST_RETRY_DELAY: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+
+/// The endpoints of an ACME CA, see RFC 8555 section 7.1.1.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    #[serde(default)]
+    meta: DirectoryMeta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryMeta {
+    #[serde(default)]
+    external_account_required: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Order {
+    pub status: String,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Authorization {
+    pub status: String,
+    pub identifier: Identifier,
+    pub challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Identifier {
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub token: Option<String>,
+    pub error: Option<Problem>,
+}
+
+/// An error returned by the CA, see RFC 8555 section 6.7.
+#[derive(Debug, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub detail: Option<String>,
+}
+
+impl Problem {
+    fn is_bad_nonce(&self) -> bool {
+        self.kind == "urn:ietf:params:acme:error:badNonce"
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({})", detail, self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// The binding of a new account to an account the CA issued out of band.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalAccountBinding {
+    pub key_id: String,
+    pub hmac_key: Vec<u8>,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: Bytes,
+}
+
+impl AcmeResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, io::Error> {
+        serde_json::from_slice(&self.body).map_err(|e| invalid_response(e.to_string()))
+    }
+}
+
+/// An ACME (RFC 8555) account at a CA, which orders certificates.
+pub struct AcmeClient {
+    http: Client<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    key: AccountKey,
+    /// The URL of the account, set once registered
+    key_id: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the directory at `directory_url`, the CA is verified with
+    /// `tls_config`.
+    pub async fn new(
+        directory_url: &str,
+        tls_config: ClientConfig,
+        key: AccountKey,
+    ) -> Result<AcmeClient, io::Error> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let http = Client::builder().build(connector);
+        let response = send_unsigned(&http, Method::GET, directory_url).await?;
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(io::Error::other)?;
+        let directory = serde_json::from_slice(&body).map_err(|e| {
+            invalid_response(format!("Invalid directory '{}': {}", directory_url, e))
+        })?;
+        Ok(AcmeClient {
+            http,
+            directory,
+            key,
+            key_id: None,
+            nonce: None,
+        })
+    }
+
+    /// Registers the account key, or looks up the account it already belongs to.
+    pub async fn register(
+        &mut self,
+        email: &str,
+        external_account: Option<&ExternalAccountBinding>,
+    ) -> Result<(), io::Error> {
+        let mut payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": [format!("mailto:{}", email)],
+        });
+        match external_account {
+            Some(binding) => {
+                payload["externalAccountBinding"] = external_account_binding(
+                    &binding.key_id,
+                    &binding.hmac_key,
+                    &self.key.jwk()?,
+                    &self.directory.new_account,
+                )?;
+            }
+            None if self.directory.meta.external_account_required => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The CA requires an external account binding, configure 'eab'",
+                ));
+            }
+            None => {}
+        }
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+        let key_id = response
+            .location
+            .ok_or_else(|| invalid_response("Account without location".to_string()))?;
+        self.key_id = Some(key_id);
+        Ok(())
+    }
+
+    /// Orders a certificate for `names`, returns the URL of the order and the order.
+    pub async fn new_order(&mut self, names: &[&str]) -> Result<(String, Order), io::Error> {
+        let identifiers = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect::<Vec<_>>();
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .location
+            .clone()
+            .ok_or_else(|| invalid_response("Order without location".to_string()))?;
+        Ok((order_url, response.json()?))
+    }
+
+    pub async fn authorization(&mut self, url: &str) -> Result<Authorization, io::Error> {
+        self.post(url, None).await?.json()
+    }
+
+    pub fn key_authorization(&self, token: &str) -> Result<String, io::Error> {
+        self.key.key_authorization(token)
+    }
+
+    /// Tells the CA to validate `challenge` and waits until the authorization
+    /// at `authorization_url` is no longer pending.
+    pub async fn validate(
+        &mut self,
+        challenge: &Challenge,
+        authorization_url: &str,
+    ) -> Result<(), io::Error> {
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        let authorization: Authorization = self
+            .poll(authorization_url, |it: &Authorization| &it.status)
+            .await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let problem = authorization
+            .challenges
+            .iter()
+            .find_map(|it| it.error.as_ref())
+            .map_or_else(|| "no details".to_string(), Problem::to_string);
+        Err(io::Error::other(format!(
+            "Validation of '{}' failed with status {}: {}",
+            authorization.identifier.value, authorization.status, problem
+        )))
+    }
+
+    /// Waits until the order at `url` is no longer pending or processing.
+    pub async fn poll_order(&mut self, url: &str) -> Result<Order, io::Error> {
+        self.poll(url, |it: &Order| &it.status).await
+    }
+
+    /// Submits the DER encoded `csr` for the ready `order`, the CA issues
+    /// the certificate asynchronously.
+    pub async fn finalize(&mut self, order: &Order, csr: &[u8]) -> Result<Order, io::Error> {
+        let csr = super::jws::base64url(csr);
+        self.post(&order.finalize, Some(&json!({ "csr": csr })))
+            .await?
+            .json()
+    }
+
+    /// Downloads the PEM encoded certificate chain of a valid `order`.
+    pub async fn certificate(&mut self, order: &Order) -> Result<Vec<u8>, io::Error> {
+        let url = order
+            .certificate
+            .as_ref()
+            .ok_or_else(|| invalid_response("Valid order without certificate".to_string()))?;
+        Ok(self.post(url, None).await?.body.to_vec())
+    }
+
+    async fn poll<T, F>(&mut self, url: &str, status: F) -> Result<T, io::Error>
+    where
+        T: DeserializeOwned,
+        F: Fn(&T) -> &String,
+    {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = self.post(url, None).await?.json()?;
+            if !matches!(status(&resource).as_str(), "pending" | "processing") {
+                return Ok(resource);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("'{}' is still pending", url),
+        ))
+    }
+
+    /// Sends a signed request, without a payload as POST-as-GET. Requests
+    /// failing temporarily are repeated.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<AcmeResponse, io::Error> {
+        let mut bad_nonces = 0;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.key_id {
+                Some(key_id) => protected["kid"] = json!(key_id),
+                None => protected["jwk"] = self.key.jwk()?,
+            }
+            let body = self.key.sign(&protected, payload)?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body.to_string()))
+                .map_err(io::Error::other)?;
+            let response = match self.http.request(request).await {
+                Ok(response) => response,
+                Err(e) => match retry_delay(attempt, None, None) {
+                    Some(delay) => {
+                        retry_later(url, delay, &e.to_string()).await;
+                        continue;
+                    }
+                    None => return Err(io::Error::other(e)),
+                },
+            };
+
+            self.nonce = header(&response, REPLAY_NONCE);
+            let status = response.status();
+            let location = header(&response, LOCATION.as_str());
+            let retry_after = retry_after(&response);
+            let body = to_bytes(response.into_body())
+                .await
+                .map_err(io::Error::other)?;
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            let problem = serde_json::from_slice::<Problem>(&body).ok();
+            if problem.as_ref().is_some_and(Problem::is_bad_nonce) && bad_nonces < BAD_NONCE_RETRIES
+            {
+                bad_nonces += 1;
+                metrics::ACME_REQUEST_RETRIES_TOTAL
+                    .with_label_values(&["bad_nonce"])
+                    .inc();
+                continue;
+            }
+            let error = match problem {
+                Some(problem) => format!("'{}' responded with {}: {}", url, status, problem),
+                None => format!("'{}' responded with {}", url, status),
+            };
+            match retry_delay(attempt, Some(status), retry_after) {
+                Some(delay) => retry_later(url, delay, &error).await,
+                None => return Err(io::Error::other(error)),
+            }
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, io::Error> {
+        let response = send_unsigned(&self.http, Method::HEAD, &self.directory.new_nonce).await?;
+        header(&response, REPLAY_NONCE)
+            .ok_or_else(|| unexpected_status(&self.directory.new_nonce, response.status()))
+    }
+}
+
+/// Sends a request which needs no signature, i.e. for the directory and
+/// nonces. Requests failing temporarily are repeated.
+async fn send_unsigned(
+    http: &Client<HttpsConnector<HttpConnector>>,
+    method: Method,
+    url: &str,
+) -> Result<Response<Body>, io::Error> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(url)
+            .body(Body::empty())
+            .map_err(io::Error::other)?;
+        let (status, retry_after, error) = match http.request(request).await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => (
+                Some(response.status()),
+                retry_after(&response),
+                unexpected_status(url, response.status()),
+            ),
+            Err(e) => (None, None, io::Error::other(e)),
+        };
+        match retry_delay(attempt, status, retry_after) {
+            Some(delay) => retry_later(url, delay, &error.to_string()).await,
+            None => return Err(error),
+        }
+    }
+}
+
+/// The delay before repeating a request after its `attempt`, which failed
+/// with `status` or without a response. `None` if the error is not temporary
+/// or the request was repeated often enough.
+fn retry_delay(
+    attempt: usize,
+    status: Option<StatusCode>,
+    retry_after: Option<Duration>,
+) -> Option<Duration> {
+    if attempt > REQUEST_RETRIES {
+        return None;
+    }
+    if let Some(status) = status {
+        if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+    }
+    let backoff = REQUEST_RETRY_DELAY * 2u32.pow(attempt as u32 - 1);
+    match retry_after {
+        // e.g. rate limits lasting for hours, which are left to the renewal backoff
+        Some(retry_after) if retry_after > MAX_REQUEST_RETRY_DELAY => None,
+        Some(retry_after) => Some(retry_after.max(backoff)),
+        None => Some(backoff),
+    }
+}
+
+async fn retry_later(url: &str, delay: Duration, error: &str) {
+    warn!(
+        "Repeating ACME request to '{}' in {:?}: {}",
+        url, delay, error
+    );
+    metrics::ACME_REQUEST_RETRIES_TOTAL
+        .with_label_values(&["temporary_failure"])
+        .inc();
+    sleep(delay).await;
+}
+
+/// The `Retry-After` header in seconds, HTTP dates are not supported.
+fn retry_after<T>(response: &Response<T>) -> Option<Duration> {
+    header(response, RETRY_AFTER.as_str())?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn header<T>(response: &Response<T>, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|it| it.to_str().ok())
+        .map(str::to_string)
+}
+
+fn unexpected_status(url: &str, status: StatusCode) -> io::Error {
+    invalid_response(format!("'{}' responded with {}", url, status))
+}
+
+fn invalid_response(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_temporary_failures_with_backoff() {
+        assert_eq!(retry_delay(1, None, None), Some(REQUEST_RETRY_DELAY));
+        assert_eq!(
+            retry_delay(3, Some(StatusCode::SERVICE_UNAVAILABLE), None),
+            Some(REQUEST_RETRY_DELAY * 4)
+        );
+        assert_eq!(
+            retry_delay(
+                1,
+                Some(StatusCode::TOO_MANY_REQUESTS),
+                Some(Duration::from_secs(10))
+            ),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(retry_delay(REQUEST_RETRIES + 1, None, None), None);
+    }
+
+    #[test]
+    fn does_not_repeat_permanent_failures() {
+        assert_eq!(retry_delay(1, Some(StatusCode::FORBIDDEN), None), None);
+        assert_eq!(
+            retry_delay(
+                1,
+                Some(StatusCode::TOO_MANY_REQUESTS),
+                Some(Duration::from_secs(3600))
+            ),
+            None
+        );
+    }
+}
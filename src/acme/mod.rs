@@ -0,0 +1,736 @@
+use crate::{
+    configuration::RuntimeConfig,
+    error_response::{bad_request, not_found},
+    metrics,
+    tls::{
+        load_certified_key, server_name_to_string, valid_days_left, CertificateName, UpstreamTls,
+    },
+};
+use arc_swap::ArcSwap;
+use client::AcmeClient;
+pub use client::ExternalAccountBinding;
+use hyper::{Body, Request, Response, StatusCode};
+use jws::AccountKey;
+use log::{info, warn};
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    stack::Stack,
+    x509::{
+        extension::SubjectAlternativeName, X509Extension, X509NameBuilder, X509ReqBuilder, X509,
+    },
+};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+use tokio_rustls::rustls::{
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, ServerName,
+};
+
+mod client;
+mod jws;
+
+pub const LETS_ENCRYPT_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETS_ENCRYPT_STAGING_DIRECTORY_URL: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// The ALPN protocol of the validation handshakes of TLS-ALPN-01, see RFC 8737.
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+/// The OID of the id-pe-acmeIdentifier extension of TLS-ALPN-01 certificates
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// How the CA validates the control over a name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum AcmeChallenge {
+    /// A token served via HTTP on port 80
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// A certificate served via TLS on port 443 to handshakes with the
+    /// `acme-tls/1` ALPN protocol
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    fn kind(&self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// The type of the private keys of ordered certificates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum KeyType {
+    #[default]
+    #[serde(rename = "ecdsa-p256")]
+    EcdsaP256,
+    #[serde(rename = "ecdsa-p384")]
+    EcdsaP384,
+    #[serde(rename = "rsa-2048")]
+    Rsa2048,
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+}
+
+impl KeyType {
+    fn generate(&self) -> Result<PKey<Private>, io::Error> {
+        let ec_key = |curve| {
+            let group = EcGroup::from_curve_name(curve)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)
+        };
+        let rsa_key = |bits| PKey::from_rsa(Rsa::generate(bits)?);
+        match self {
+            KeyType::EcdsaP256 => ec_key(Nid::X9_62_PRIME256V1),
+            KeyType::EcdsaP384 => ec_key(Nid::SECP384R1),
+            KeyType::Rsa2048 => rsa_key(2048),
+            KeyType::Rsa4096 => rsa_key(4096),
+        }
+        .map_err(io::Error::other)
+    }
+}
+
+/// The settings of a certificate obtained via ACME.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcmeCertificate {
+    /// Further names the certificate is valid for, besides the one it is configured for
+    pub alt_names: Vec<ServerName>,
+    pub key_type: KeyType,
+    pub directory_url: String,
+    /// PEM file with the CAs to trust for the directory, the system's root store is used if absent
+    pub ca_bundle_path: Option<PathBuf>,
+    pub email: String,
+    pub external_account: Option<ExternalAccountBinding>,
+    pub challenge: AcmeChallenge,
+    pub persist_dir: PathBuf,
+    /// Renew the certificate once it is valid for this many days or less
+    pub renew_before_days: i64,
+}
+
+impl AcmeCertificate {
+    /// All names of the certificate configured for `primary_name`.
+    pub fn certificate_names(&self, primary_name: &CertificateName) -> Vec<CertificateName> {
+        let mut names = vec![primary_name.clone()];
+        names.extend(self.alt_names.iter().cloned().map(CertificateName::Exact));
+        names
+    }
+
+    /// The directory caching the account key and certificates of this CA.
+    /// Every CA gets its own, so that e.g. staging certificates are never served
+    /// once `staging` is disabled.
+    fn cache_dir(&self) -> PathBuf {
+        let name = self
+            .directory_url
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        self.persist_dir.join(name)
+    }
+}
+
+struct OpenChallenge {
+    token: String,
+    proof: String,
+}
+
+pub struct AcmeHandler {
+    challenges: Arc<Mutex<Vec<OpenChallenge>>>,
+    /// The TLS-ALPN-01 certificates by the name they validate
+    tls_alpn_challenges: Arc<Mutex<HashMap<String, Arc<CertifiedKey>>>>,
+}
+
+impl AcmeHandler {
+    pub fn new() -> AcmeHandler {
+        AcmeHandler {
+            challenges: Arc::new(Mutex::new(Vec::new())),
+            tls_alpn_challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn add_challenge(&self, token: &str, proof: String) {
+        let challenge = OpenChallenge {
+            token: token.to_string(),
+            proof,
+        };
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.push(challenge);
+    }
+
+    fn get_proof_for_challenge(&self, token: &str) -> Option<String> {
+        let challenges = self.challenges.lock().unwrap();
+        challenges
+            .iter()
+            .find(|c| c.token == token)
+            .map(|c| c.proof.clone())
+    }
+
+    fn remove_challenge(&self, token: &str) {
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges
+            .iter()
+            .position(|c| c.token == token)
+            .map(|i| challenges.remove(i));
+    }
+
+    /// The certificate answering the TLS-ALPN-01 challenge for `name`, if one is open.
+    pub fn tls_alpn_challenge(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let challenges = self.tls_alpn_challenges.lock().unwrap();
+        challenges.get(name).cloned()
+    }
+
+    /// Returns the cached certificate for `primary_name` if it is valid for
+    /// more than `min_days_left` days and covers all names, otherwise orders a
+    /// new one.
+    pub async fn initiate_challenge(
+        &self,
+        settings: &AcmeCertificate,
+        primary_name: &str,
+        min_days_left: i64,
+    ) -> Result<CertifiedKey, io::Error> {
+        let mut names = vec![primary_name.to_string()];
+        names.extend(settings.alt_names.iter().map(server_name_to_string));
+        let cache_dir = settings.cache_dir();
+        let certificate_path = cache_dir.join(format!("{}.crt", primary_name));
+        let private_key_path = cache_dir.join(format!("{}.key", primary_name));
+        if let Ok(certified_key) = load_certified_key(&certificate_path, &private_key_path) {
+            let covers_names = dns_names(&certified_key)
+                .is_some_and(|it| names.iter().all(|name| it.contains(name)));
+            if covers_names && valid_days_left(&certified_key).is_some_and(|it| it > min_days_left)
+            {
+                return Ok(certified_key);
+            }
+        }
+
+        fs::create_dir_all(&cache_dir)?;
+        let account_key =
+            load_or_create_account_key(&cache_dir.join(format!("account_{}.key", settings.email)))?;
+        let tls_config = UpstreamTls {
+            ca_bundle_path: settings.ca_bundle_path.clone(),
+            server_name: None,
+            client_certificate: None,
+            insecure_skip_verify: false,
+        }
+        .client_config()?;
+        let mut client = AcmeClient::new(&settings.directory_url, tls_config, account_key).await?;
+        client
+            .register(&settings.email, settings.external_account.as_ref())
+            .await?;
+
+        let private_key = settings.key_type.generate()?;
+        let ordered = self
+            .order_certificate(&mut client, &names, &private_key, settings.challenge)
+            .await;
+        let result = match &ordered {
+            Ok(_) => "valid",
+            Err(e) => {
+                warn!("ACME order for '{}' failed: {}", primary_name, e);
+                report_order_status(primary_name, "failed");
+                "failed"
+            }
+        };
+        metrics::ACME_ORDERS_TOTAL
+            .with_label_values(&[primary_name, result])
+            .inc();
+        let certificate = ordered?;
+
+        write_private(
+            &private_key_path,
+            &private_key
+                .private_key_to_pem_pkcs8()
+                .map_err(io::Error::other)?,
+        )?;
+        fs::write(&certificate_path, certificate)?;
+        load_certified_key(&certificate_path, &private_key_path)
+    }
+
+    /// Orders a certificate for `names` and answers their challenges of type
+    /// `challenge_type`, returns the PEM encoded certificate chain. The order
+    /// is reported under the first name.
+    async fn order_certificate(
+        &self,
+        client: &mut AcmeClient,
+        names: &[String],
+        private_key: &PKey<Private>,
+        challenge_type: AcmeChallenge,
+    ) -> Result<Vec<u8>, io::Error> {
+        let name = &names[0];
+        let (order_url, order) = client
+            .new_order(&names.iter().map(String::as_str).collect::<Vec<_>>())
+            .await?;
+        report_order_status(name, &order.status);
+        for authorization_url in &order.authorizations {
+            let authorization = client.authorization(authorization_url).await?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let identifier = &authorization.identifier.value;
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|it| it.kind == challenge_type.kind())
+                .ok_or_else(|| {
+                    io::Error::other(format!(
+                        "The CA offers no {} challenge for '{}'",
+                        challenge_type.kind(),
+                        identifier
+                    ))
+                })?;
+            let token = challenge.token.as_deref().ok_or_else(|| {
+                io::Error::other(format!("{} challenge without token", challenge_type.kind()))
+            })?;
+            info!(
+                "Validating '{}' via {} for the ACME order of '{}'",
+                identifier,
+                challenge_type.kind(),
+                name
+            );
+            let key_authorization = client.key_authorization(token)?;
+            match challenge_type {
+                AcmeChallenge::Http01 => self.add_challenge(token, key_authorization),
+                AcmeChallenge::TlsAlpn01 => {
+                    let certificate = tls_alpn_certificate(identifier, &key_authorization)?;
+                    let mut challenges = self.tls_alpn_challenges.lock().unwrap();
+                    challenges.insert(identifier.clone(), Arc::new(certificate));
+                }
+            }
+            let validated = client.validate(challenge, authorization_url).await;
+            match challenge_type {
+                AcmeChallenge::Http01 => self.remove_challenge(token),
+                AcmeChallenge::TlsAlpn01 => {
+                    let mut challenges = self.tls_alpn_challenges.lock().unwrap();
+                    challenges.remove(identifier);
+                }
+            }
+            validated?;
+        }
+
+        let order = client.poll_order(&order_url).await?;
+        report_order_status(name, &order.status);
+        if order.status != "ready" {
+            return Err(io::Error::other(format!(
+                "Order is {} instead of ready: {}",
+                order.status,
+                order
+                    .error
+                    .as_ref()
+                    .map_or_else(|| "no details".to_string(), ToString::to_string)
+            )));
+        }
+        let finalized = client
+            .finalize(&order, &certificate_request(names, private_key)?)
+            .await?;
+        report_order_status(name, &finalized.status);
+        let order = client.poll_order(&order_url).await?;
+        if order.status != finalized.status {
+            report_order_status(name, &order.status);
+        }
+        if order.status != "valid" {
+            return Err(io::Error::other(format!(
+                "Order finished with status {}: {}",
+                order.status,
+                order
+                    .error
+                    .as_ref()
+                    .map_or_else(|| "no details".to_string(), ToString::to_string)
+            )));
+        }
+        client.certificate(&order).await
+    }
+
+    fn is_challenge(&self, request: &Request<Body>) -> bool {
+        request
+            .uri()
+            .path()
+            .starts_with("/.well-known/acme-challenge/")
+    }
+
+    pub fn respond_to_challenge(&self, request: &Request<Body>) -> Option<Response<Body>> {
+        if !self.is_challenge(request) {
+            None
+        } else {
+            Some(request.uri().path().split('/').next_back().map_or_else(
+                || bad_request("Unable to extract token from last path param!"),
+                |token| {
+                    self.get_proof_for_challenge(token)
+                        .map_or_else(not_found, |proof| {
+                            Response::builder()
+                                .status(StatusCode::OK)
+                                .body(Body::from(proof))
+                                .unwrap()
+                        })
+                },
+            ))
+        }
+    }
+}
+
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Spreads the renewals of several instances sharing a CA account
+const RENEWAL_CHECK_JITTER: Duration = Duration::from_secs(10 * 60);
+const RENEWAL_MIN_BACKOFF: Duration = Duration::from_secs(60);
+const RENEWAL_MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Renews the ACME certificates of the config once they are valid for
+/// `renew_before_days` or less and swaps the renewed certificates in. Failed
+/// renewals are retried with an exponential backoff.
+///
+/// Certificates missing in the config, e.g. all of them after startup, are
+/// loaded from the cache or obtained the same way.
+pub async fn renew_certificates(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+    // the number of failed attempts and the time of the next one per name
+    let mut failures: HashMap<CertificateName, (u32, Instant)> = HashMap::new();
+    loop {
+        let current = config.load_full();
+        failures
+            .retain(|certificate_name, _| current.acme_certificates.contains_key(certificate_name));
+        for (certificate_name, settings) in &current.acme_certificates {
+            if failures
+                .get(certificate_name)
+                .is_some_and(|(_, retry_at)| *retry_at > Instant::now())
+            {
+                continue;
+            }
+            let name = certificate_name.to_string();
+            let missing = match current.certificates.get(certificate_name) {
+                None => true,
+                Some(certified_key) => match valid_days_left(certified_key) {
+                    Some(days_left) if days_left <= settings.renew_before_days => {
+                        info!(
+                            "Renewing ACME certificate for '{}', it expires in {} days",
+                            name, days_left
+                        );
+                        false
+                    }
+                    _ => continue,
+                },
+            };
+
+            match renew_certificate(&current, settings, &name).await {
+                Ok(certified_key) => {
+                    failures.remove(certificate_name);
+                    match valid_days_left(&certified_key) {
+                        Some(days_left) if days_left <= settings.renew_before_days => warn!(
+                            "Renewed ACME certificate for '{}' expires in {} days already, consider lowering renew_before_days",
+                            name, days_left
+                        ),
+                        _ if missing => info!("Loaded ACME certificate for '{}'", name),
+                        _ => info!("Renewed ACME certificate for '{}'", name),
+                    }
+                    config.rcu(|config| {
+                        let mut config = RuntimeConfig::clone(config);
+                        for it in settings.certificate_names(certificate_name) {
+                            config.certificates.insert(it, certified_key.clone());
+                        }
+                        config
+                    });
+                }
+                Err(e) => {
+                    let (attempts, retry_at) = failures
+                        .entry(certificate_name.clone())
+                        .or_insert((0, Instant::now()));
+                    *attempts += 1;
+                    let backoff = renewal_backoff(*attempts);
+                    *retry_at = Instant::now() + backoff;
+                    warn!(
+                        "Could not renew ACME certificate for '{}', retrying in {:?}: {}",
+                        name, backoff, e
+                    );
+                }
+            }
+        }
+
+        let jitter = thread_rng().gen_range(Duration::ZERO..=RENEWAL_CHECK_JITTER);
+        let next_check = Instant::now() + RENEWAL_CHECK_INTERVAL + jitter;
+        let next_retry = failures.values().map(|(_, retry_at)| *retry_at).min();
+        sleep_until(next_retry.map_or(next_check, |it| it.min(next_check))).await;
+    }
+}
+
+/// A certificate for `name` valid for more than `renew_before_days`, from the
+/// cache if possible.
+async fn renew_certificate(
+    config: &RuntimeConfig,
+    settings: &AcmeCertificate,
+    name: &str,
+) -> Result<CertifiedKey, io::Error> {
+    config
+        .shared_data
+        .acme_handler
+        .initiate_challenge(settings, name, settings.renew_before_days)
+        .await
+}
+
+fn load_or_create_account_key(path: &Path) -> Result<AccountKey, io::Error> {
+    match fs::read(path) {
+        Ok(pem) => AccountKey::from_pem(&pem),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = AccountKey::generate()?;
+            write_private(path, &key.to_pem()?)?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a private key readable by the owner only.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+/// A DER encoded certificate signing request for `names`, the first one is
+/// the common name.
+fn certificate_request(
+    names: &[String],
+    private_key: &PKey<Private>,
+) -> Result<Vec<u8>, io::Error> {
+    let mut subject = X509NameBuilder::new().map_err(io::Error::other)?;
+    subject
+        .append_entry_by_text("CN", &names[0])
+        .map_err(io::Error::other)?;
+    let mut builder = X509ReqBuilder::new().map_err(io::Error::other)?;
+    builder
+        .set_subject_name(&subject.build())
+        .map_err(io::Error::other)?;
+    builder.set_pubkey(private_key).map_err(io::Error::other)?;
+    let mut alternative_names = SubjectAlternativeName::new();
+    for name in names {
+        alternative_names.dns(name);
+    }
+    let alternative_names = alternative_names
+        .build(&builder.x509v3_context(None))
+        .map_err(io::Error::other)?;
+    let mut extensions = Stack::new().map_err(io::Error::other)?;
+    extensions
+        .push(alternative_names)
+        .map_err(io::Error::other)?;
+    builder
+        .add_extensions(&extensions)
+        .map_err(io::Error::other)?;
+    builder
+        .sign(private_key, MessageDigest::sha256())
+        .map_err(io::Error::other)?;
+    builder.build().to_der().map_err(io::Error::other)
+}
+
+/// The self-signed certificate proving the control over `name` for
+/// TLS-ALPN-01. Its critical acmeIdentifier extension holds the SHA-256
+/// digest of the key authorization.
+fn tls_alpn_certificate(name: &str, key_authorization: &str) -> Result<CertifiedKey, io::Error> {
+    let key = KeyType::EcdsaP256.generate()?;
+    let mut subject = X509NameBuilder::new().map_err(io::Error::other)?;
+    subject
+        .append_entry_by_text("CN", name)
+        .map_err(io::Error::other)?;
+    let subject = subject.build();
+
+    let mut builder = X509::builder().map_err(io::Error::other)?;
+    builder.set_version(2).map_err(io::Error::other)?;
+    let mut serial_number = BigNum::new().map_err(io::Error::other)?;
+    serial_number
+        .rand(127, MsbOption::MAYBE_ZERO, false)
+        .map_err(io::Error::other)?;
+    let serial_number = serial_number.to_asn1_integer().map_err(io::Error::other)?;
+    builder
+        .set_serial_number(&serial_number)
+        .map_err(io::Error::other)?;
+    builder
+        .set_subject_name(&subject)
+        .map_err(io::Error::other)?;
+    builder
+        .set_issuer_name(&subject)
+        .map_err(io::Error::other)?;
+    builder.set_pubkey(&key).map_err(io::Error::other)?;
+    let not_before = Asn1Time::days_from_now(0).map_err(io::Error::other)?;
+    builder
+        .set_not_before(&not_before)
+        .map_err(io::Error::other)?;
+    let not_after = Asn1Time::days_from_now(7).map_err(io::Error::other)?;
+    builder
+        .set_not_after(&not_after)
+        .map_err(io::Error::other)?;
+    let alternative_names = SubjectAlternativeName::new()
+        .dns(name)
+        .build(&builder.x509v3_context(None, None))
+        .map_err(io::Error::other)?;
+    builder
+        .append_extension(alternative_names)
+        .map_err(io::Error::other)?;
+    let digest =
+        hash(MessageDigest::sha256(), key_authorization.as_bytes()).map_err(io::Error::other)?;
+    // the extension value is the DER encoded OCTET STRING of the digest
+    let mut acme_identifier = vec![0x04, digest.len() as u8];
+    acme_identifier.extend_from_slice(&digest);
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID).map_err(io::Error::other)?;
+    let contents = Asn1OctetString::new_from_bytes(&acme_identifier).map_err(io::Error::other)?;
+    let acme_identifier =
+        X509Extension::new_from_der(&oid, true, &contents).map_err(io::Error::other)?;
+    builder
+        .append_extension(acme_identifier)
+        .map_err(io::Error::other)?;
+    builder
+        .sign(&key, MessageDigest::sha256())
+        .map_err(io::Error::other)?;
+
+    let certificate = Certificate(builder.build().to_der().map_err(io::Error::other)?);
+    let private_key = PrivateKey(key.private_key_to_pkcs8().map_err(io::Error::other)?);
+    let signing_key = any_supported_type(&private_key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid private key"))?;
+    Ok(CertifiedKey::new(vec![certificate], signing_key))
+}
+
+const ORDER_STATUSES: [&str; 6] = [
+    "pending",
+    "ready",
+    "processing",
+    "valid",
+    "invalid",
+    "failed",
+];
+
+/// Logs the `status` of the ACME order for `certificate` and exposes it in
+/// the `acme_order_status` metric.
+fn report_order_status(certificate: &str, status: &str) {
+    info!("ACME order for '{}' is {}", certificate, status);
+    for it in ORDER_STATUSES {
+        metrics::ACME_ORDER_STATUS
+            .with_label_values(&[certificate, it])
+            .set((it == status).into());
+    }
+}
+
+/// The DNS names the end-entity certificate of `certified_key` is valid for.
+fn dns_names(certified_key: &CertifiedKey) -> Option<Vec<String>> {
+    let certificate = X509::from_der(&certified_key.end_entity_cert().ok()?.0).ok()?;
+    Some(
+        certificate
+            .subject_alt_names()?
+            .iter()
+            .filter_map(|it| it.dnsname().map(str::to_string))
+            .collect(),
+    )
+}
+
+/// The delay before the next renewal attempt after `attempts` failed ones.
+fn renewal_backoff(attempts: u32) -> Duration {
+    RENEWAL_MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(RENEWAL_MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::{issue, runtime_config};
+    use openssl::x509::X509Req;
+
+    #[test]
+    fn creates_tls_alpn_certificates_with_the_acme_identifier() {
+        let certified_key = tls_alpn_certificate("example.com", "token.thumbprint").unwrap();
+
+        let certificate = X509::from_der(&certified_key.cert[0].0).unwrap();
+        let text = String::from_utf8(certificate.to_text().unwrap()).unwrap();
+        assert!(text.contains("DNS:example.com"));
+        let digest = hash(MessageDigest::sha256(), b"token.thumbprint").unwrap();
+        let mut extension = vec![0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+        // critical, followed by the octet string wrapping the DER encoded digest
+        extension.extend_from_slice(&[0x01, 0x01, 0xff, 0x04, 0x22, 0x04, 0x20]);
+        extension.extend_from_slice(&digest);
+        assert!(certificate
+            .to_der()
+            .unwrap()
+            .windows(extension.len())
+            .any(|it| it == extension.as_slice()));
+    }
+
+    #[test]
+    fn requests_certificates_for_all_names() {
+        let names = ["example.com".to_string(), "www.example.com".to_string()];
+        let key = KeyType::EcdsaP256.generate().unwrap();
+
+        let request = X509Req::from_der(&certificate_request(&names, &key).unwrap()).unwrap();
+
+        assert!(request.verify(&key).unwrap());
+        let text = String::from_utf8(request.to_text().unwrap()).unwrap();
+        assert!(text.contains("CN = example.com") || text.contains("CN=example.com"));
+        assert!(text.contains("DNS:example.com, DNS:www.example.com"));
+    }
+
+    #[tokio::test]
+    async fn loads_cached_certificates_missing_in_the_config() {
+        let settings = AcmeCertificate {
+            alt_names: Vec::new(),
+            key_type: KeyType::default(),
+            directory_url: "https://acme.invalid/directory".into(),
+            ca_bundle_path: None,
+            email: "admin@example.com".into(),
+            external_account: None,
+            challenge: AcmeChallenge::default(),
+            persist_dir: std::env::temp_dir()
+                .join(format!("rust-strom-acme-{}", std::process::id())),
+            renew_before_days: 7,
+        };
+        let (ca, ca_key) = issue("RustStrom Test CA", None);
+        let (certificate, key) = issue("example.com", Some((&ca, &ca_key)));
+        fs::create_dir_all(settings.cache_dir()).unwrap();
+        fs::write(
+            settings.cache_dir().join("example.com.crt"),
+            certificate.to_pem().unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            settings.cache_dir().join("example.com.key"),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let name = CertificateName::try_from("example.com").unwrap();
+        let mut config = runtime_config(&[], None, None);
+        config.acme_certificates.insert(name.clone(), settings);
+        let config = Arc::new(ArcSwap::from_pointee(config));
+
+        tokio::spawn(renew_certificates(config.clone()));
+
+        for _ in 0..50 {
+            if config.load().certificates.contains_key(&name) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("The cached certificate was not loaded");
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(renewal_backoff(1), RENEWAL_MIN_BACKOFF);
+        assert_eq!(renewal_backoff(2), RENEWAL_MIN_BACKOFF * 2);
+        assert_eq!(renewal_backoff(3), RENEWAL_MIN_BACKOFF * 4);
+        assert_eq!(renewal_backoff(100), RENEWAL_MAX_BACKOFF);
+    }
+}
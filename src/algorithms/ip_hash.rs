@@ -19,7 +19,7 @@ impl LoadBalancingStrategy for IPHash {
         context: &'l Context<'l>,
     ) -> RequestForwarder<'l> {
         let mut hasher = FnvHasher::default();
-        context.client_address.ip().hash(&mut hasher);
+        context.client_ip.hash(&mut hasher);
         let index = (hasher.finish() % (context.backend_addresses.len() as u64)) as usize;
         let address = &context.backend_addresses[index];
         RequestForwarder::new(address)
@@ -35,6 +35,7 @@ mod tests {
         let request = Request::builder().body(Body::empty()).unwrap();
         let context = Context {
             client_address: &"127.0.0.1:3000".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
             backend_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2"],
         };
         let strategy = IPHash::new();
@@ -83,6 +84,7 @@ mod tests {
             let request = Request::builder().body(Body::empty()).unwrap();
             let context = Context {
                 client_address: addr,
+                client_ip: addr.ip(),
                 backend_addresses,
             };
             let backend = strategy.select_backend(&request, &context).backend_address;
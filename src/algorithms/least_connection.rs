@@ -72,6 +72,7 @@ mod tests {
 
         let context = Context {
             client_address: &"127.0.0.1:3000".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
             backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
         };
 
@@ -91,6 +92,7 @@ mod tests {
 
         let context = Context {
             client_address: &"127.0.0.1:3000".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
             backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
         };
 
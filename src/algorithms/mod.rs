@@ -1,11 +1,17 @@
 use crate::{
-    http_client::StrategyNotifyHttpConnector,
-    middleware::{self, Middleware, MiddlewareChain},
-    server::Scheme,
+    error_response::{gateway_timeout, BackendFailure},
+    middleware::{self, Middleware},
+    server::{BackendPool, Scheme},
+    timeouts::{with_body_timeouts, TimeoutKind, Timeouts},
 };
 use async_trait::async_trait;
-use hyper::{Body, Client, Request, Response, Uri};
-use std::{convert::identity, fmt::Debug, net::SocketAddr};
+use hyper::{Body, Request, Response, Uri};
+use std::{
+    convert::identity,
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::time::Instant;
 
 pub mod ip_hash;
 pub mod least_connection;
@@ -39,17 +45,19 @@ pub trait LoadBalancingStrategy: Send + Sync + std::fmt::Debug {
 
 pub struct Context<'l> {
     pub client_address: &'l SocketAddr,
+    /// The address of the client, resolved from the forwarding headers if the
+    /// request was sent by a trusted proxy.
+    pub client_ip: IpAddr,
     pub backend_addresses: &'l [&'l str],
 }
 
 /// A struct representing a backend server and allowing a final transformation
 /// of the response before it is returned to the calling client.
 ///
-/// [`RequestForwarder`] implements [`Middleware`] for convenience, but you can
-/// not call [`forward_request`](RequestForwarder::forward_request) on it,
-/// because you don't get access to the backend server address to construct the
-/// [`middleware::Context`]. Instead you should call
-/// [`forward_request_to_backend`][].
+/// [`RequestForwarder`] implements [`Middleware`] for convenience, but you
+/// should not call [`forward_request`](RequestForwarder::forward_request) on
+/// it. The backend is selected once the middleware chain of the pool ran, for
+/// every attempt, so you should call [`forward_request_to_backend`][] instead.
 ///
 /// [`forward_request_to_backend`]: RequestForwarder::forward_request_to_backend
 pub struct RequestForwarder<'l> {
@@ -89,49 +97,65 @@ impl<'l> RequestForwarder<'l> {
         }
     }
 
-    /// Forwards the `request` through `chain` to the backend server and applies
-    /// the final response transformation of this [`RequestForwarder`].
+    /// Forwards the `request`, which passed the middleware chain of `pool`, to
+    /// the backend server using the pool's client and applies the final
+    /// response transformation of this [`RequestForwarder`].
+    ///
+    /// Returns a gateway timeout response if the response headers did not
+    /// arrive within the `timeouts`, and aborts the response body if it is not
+    /// received completely within them.
     pub async fn forward_request_to_backend(
         &self,
         request: Request<Body>,
-        chain: &MiddlewareChain,
-        client_scheme: &Scheme,
-        client_address: &SocketAddr,
-        client: &Client<StrategyNotifyHttpConnector, Body>,
+        pool: &BackendPool,
+        context: &middleware::Context<'_>,
+        timeouts: Timeouts,
     ) -> Response<Body> {
-        let context = middleware::Context {
-            client_scheme,
-            client_address,
-            backend_uri: self.backend_uri(&request),
-            client,
+        let backend_uri = self.backend_uri(&request, &pool.backend_scheme());
+        let start = Instant::now();
+        let deadline = timeouts.total.map(|total| start + total);
+        let header_limit = match (timeouts.response_header, timeouts.total) {
+            (Some(header), Some(total)) if total < header => Some((total, TimeoutKind::Total)),
+            (Some(header), _) => Some((header, TimeoutKind::ResponseHeader)),
+            (None, total) => total.map(|total| (total, TimeoutKind::Total)),
+        };
+        let forward = async {
+            let response = middleware::send_request(request, context, &backend_uri, &pool.client);
+            self.modify_response(response.await, context).await
+        };
+        let response = match header_limit {
+            Some((limit, kind)) => match tokio::time::timeout(limit, forward).await {
+                Ok(response) => response,
+                Err(_) => {
+                    log::warn!(
+                        "Request to backend {} timed out, {} of {:?} exceeded",
+                        self.backend_address,
+                        kind,
+                        limit
+                    );
+                    crate::metrics::HTTP_TIMEOUTS_TOTAL.inc();
+                    let mut response = gateway_timeout(kind);
+                    response.extensions_mut().insert(BackendFailure::Timeout);
+                    return response;
+                }
+            },
+            None => forward.await,
         };
 
-        // Add request timeout protection (30 seconds default)
-        let timeout_duration = std::time::Duration::from_secs(30);
-        match tokio::time::timeout(
-            timeout_duration,
-            self.forward_request(request, chain, &context),
-        )
-        .await
-        {
-            Ok(response) => response,
-            Err(_) => {
-                // Timeout occurred
-                log::warn!(
-                    "Request to backend {} timed out after {:?}",
-                    self.backend_address,
-                    timeout_duration
-                );
-                crate::metrics::HTTP_TIMEOUTS_TOTAL.inc();
-                crate::error_response::gateway_timeout()
-            }
-        }
+        let (parts, body) = response.into_parts();
+        let body = with_body_timeouts(
+            body,
+            self.backend_address.to_string(),
+            deadline,
+            timeouts.idle_body,
+        );
+        Response::from_parts(parts, body)
     }
 
-    fn backend_uri(&self, request: &Request<Body>) -> Uri {
+    fn backend_uri(&self, request: &Request<Body>, backend_scheme: &Scheme) -> Uri {
         let path = request.uri().path_and_query().unwrap().clone();
         Uri::builder()
-            .scheme("http")
+            .scheme(backend_scheme.to_string().as_str())
             .authority(self.backend_address)
             .path_and_query(path)
             .build()
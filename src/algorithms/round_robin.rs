@@ -42,6 +42,7 @@ mod tests {
         let address = "127.0.0.1:1";
         let context = Context {
             client_address: &"127.0.0.1:3000".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
             backend_addresses: &mut [address],
         };
         let strategy = RoundRobin::new();
@@ -67,6 +68,7 @@ mod tests {
         let address_2 = "127.0.0.1:2";
         let context = Context {
             client_address: &"127.0.0.1:3000".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
             backend_addresses: &mut [address_1, address_2],
         };
         let strategy = RoundRobin::new();
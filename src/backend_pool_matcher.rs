@@ -1,4 +1,4 @@
-use std::{collections::HashMap, ops::Deref, str::FromStr};
+use std::{collections::HashMap, fmt, ops::Deref, str::FromStr};
 
 use hyper::{header::HOST, Body, Method, Request};
 use pom::parser::*;
@@ -76,6 +76,45 @@ impl BackendPoolMatcher {
     }
 }
 
+/// Formats the matcher in the same syntax it is parsed from, e.g. to label
+/// metrics of a backend pool.
+impl fmt::Display for BackendPoolMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn quote(string: &str) -> String {
+            format!("'{}'", string.replace('\'', "\\'"))
+        }
+        fn operand(matcher: &BackendPoolMatcher) -> String {
+            match matcher {
+                BackendPoolMatcher::And(_, _) | BackendPoolMatcher::Or(_, _) => {
+                    format!("({})", matcher)
+                }
+                _ => matcher.to_string(),
+            }
+        }
+
+        match self {
+            BackendPoolMatcher::Host(host) => write!(f, "Host({})", quote(host)),
+            BackendPoolMatcher::HostRegexp(regex) => {
+                write!(f, "HostRegexp({})", quote(regex.as_str()))
+            }
+            BackendPoolMatcher::Method(method) => write!(f, "Method({})", quote(method.as_str())),
+            BackendPoolMatcher::Path(path) => write!(f, "Path({})", quote(path)),
+            BackendPoolMatcher::PathRegexp(regex) => {
+                write!(f, "PathRegexp({})", quote(regex.as_str()))
+            }
+            BackendPoolMatcher::Query(key, value) => {
+                write!(f, "Query({}, {})", quote(key), quote(value))
+            }
+            BackendPoolMatcher::And(left, right) => {
+                write!(f, "{} && {}", operand(left), operand(right))
+            }
+            BackendPoolMatcher::Or(left, right) => {
+                write!(f, "{} || {}", operand(left), operand(right))
+            }
+        }
+    }
+}
+
 /// A PEG parser for generating BackendPoolMatcher rules
 ///
 /// # Examples:
@@ -407,4 +446,17 @@ mod tests {
         assert!(matcher.matches(&request_1));
         assert!(matcher.matches(&request_2));
     }
+
+    #[test]
+    fn display_round_trips_through_parser() {
+        let input = to_char_vec("(Host('1') || Host('2\\'')) && Query('admin', 'true')");
+        let matcher = parser().parse(&input).unwrap();
+
+        assert_eq!(
+            matcher.to_string(),
+            "(Host('1') || Host('2\\'')) && Query('admin', 'true')"
+        );
+        let output: Vec<char> = matcher.to_string().chars().collect();
+        assert_eq!(parser().parse(&output), Ok(matcher));
+    }
 }
@@ -1,19 +1,36 @@
 use crate::{
-    acme::AcmeHandler,
+    acme::{
+        AcmeCertificate, AcmeChallenge, AcmeHandler, ExternalAccountBinding, KeyType,
+        LETS_ENCRYPT_DIRECTORY_URL, LETS_ENCRYPT_STAGING_DIRECTORY_URL,
+    },
     algorithms::{
         ip_hash::IPHash, least_connection::LeastConnection, random::Random,
         round_robin::RoundRobin, sticky_cookie::StickyCookie, LoadBalancingStrategy,
     },
-    health::{HealthConfig, Healthiness},
+    backend_pool_matcher::BackendPoolMatcher,
+    forwarded::TrustedProxies,
+    health::HealthConfig,
+    http_client::{BackendProtocol, Http2Settings},
+    listeners::Listener,
     middleware::{
         authentication::Authentication, compression::Compression,
         custom_error_pages::CustomErrorPages, https_redirector::HttpsRedirector,
         maxbodysize::MaxBodySize, rate_limiter::RateLimiter, Middleware, MiddlewareChain,
     },
+    ocsp::OcspSource,
+    retry::{RetryBudget, RetryCondition, RetryPolicy},
     server::{BackendPool, BackendPoolBuilder, Scheme, SharedData},
-    tls::{certified_key_from_acme_certificate, load_certified_key},
+    session_tickets::load_ticket_keys,
+    timeouts::{TimeoutOverride, Timeouts},
+    tls::{
+        load_certified_key, CertificateName, ClientAuth, ClientCertificateHeaders,
+        SessionResumption, TlsPolicy, UpstreamTls, DEFAULT_SESSION_CACHE_SIZE,
+    },
 };
 use arc_swap::ArcSwap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::header::HeaderName;
+use ipnet::IpNet;
 use log::{info, trace, warn};
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use serde::Deserialize;
@@ -23,14 +40,16 @@ use std::{
     error::Error,
     fmt::Debug,
     fs, io,
-    net::SocketAddr,
     ops::Deref,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{mpsc::channel, Arc},
     thread::spawn,
     time::Duration,
 };
-use tokio::sync::watch;
+use tokio::{
+    sync::{mpsc, watch},
+    time::sleep,
+};
 use tokio_rustls::rustls::{sign::CertifiedKey, ServerName};
 use toml::{value::Table, Value};
 
@@ -40,7 +59,7 @@ pub async fn read_initial_config<P: AsRef<Path>>(
     let acme_handler = Arc::new(AcmeHandler::new());
     // Don't initialize ACME certificates on startup, because the HTTP listener is not running yet
     let init_acme = false;
-    let config = read_runtime_config(&path, acme_handler, init_acme)
+    let config = read_runtime_config(&path, acme_handler, &[], init_acme)
         .await
         .map_err(|e| {
             io::Error::new(
@@ -61,9 +80,10 @@ where
         let acme_handler = old_config.shared_data.acme_handler.clone();
         match receiver.borrow().deref() {
             DebouncedEvent::Write(path) => {
-                match read_runtime_config(&path, acme_handler, true).await {
-                    Ok(new_config) => {
-                        warn_about_ineffectual_config_changes(&old_config, &new_config);
+                let previous_pools = &old_config.shared_data.backend_pools;
+                match read_runtime_config(&path, acme_handler, previous_pools, true).await {
+                    Ok(mut new_config) => {
+                        carry_over_ocsp_staples(&mut new_config, &old_config);
                         config.store(Arc::new(new_config));
                         info!("Reloaded configuration");
                     }
@@ -80,21 +100,111 @@ where
     }
 }
 
-fn warn_about_ineffectual_config_changes(old: &RuntimeConfig, new: &RuntimeConfig) {
-    if old.http_address != new.http_address {
-        warn!(
-            "A restart is required for the new http_address '{}' to take effect",
-            new.http_address
-        );
+/// Watches the files of all `Local` certificates and swaps in a certificate
+/// once its files changed, without reloading the rest of the config. The old
+/// certificate is kept if the new files can not be loaded, e.g. because the key
+/// does not belong to the certificate.
+///
+/// The directories containing the files are watched, so that files replaced
+/// by a rename (e.g. by certbot or Kubernetes secret volumes) are noticed.
+pub async fn watch_certificates(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+    let (sender, receiver) = channel();
+    let mut watcher = watcher(sender, Duration::from_secs(1)).map_err(map_notify_error)?;
+    let (async_sender, mut events) = mpsc::unbounded_channel();
+    spawn(move || {
+        while let Ok(event) = receiver.recv() {
+            if async_sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut watched: HashSet<PathBuf> = HashSet::new();
+    loop {
+        let directories = certificate_directories(&config.load());
+        for directory in watched.difference(&directories) {
+            let _ = watcher.unwatch(directory);
+        }
+        for directory in directories.difference(&watched) {
+            if let Err(e) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+                warn!(
+                    "Could not watch certificates in '{}': {}",
+                    directory.display(),
+                    map_notify_error(e)
+                );
+            }
+        }
+        watched = directories;
+
+        tokio::select! {
+            event = events.recv() => match event.ok_or_else(|| broken_pipe("Certificate watcher stopped"))? {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Chmod(path)
+                | DebouncedEvent::Remove(path)
+                | DebouncedEvent::Rename(_, path) => reload_certificates(&config, &path),
+                e => trace!("{:?}", e),
+            },
+            // pick up certificates added by config reloads
+            _ = sleep(CERTIFICATE_WATCH_RESYNC_INTERVAL) => {}
+        }
     }
-    if old.https_address != new.https_address {
-        warn!(
-            "A restart is required for the new https_address '{}' to take effect",
-            new.https_address
-        );
+}
+
+const CERTIFICATE_WATCH_RESYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+fn certificate_directories(config: &RuntimeConfig) -> HashSet<PathBuf> {
+    config
+        .certificate_files
+        .values()
+        .flat_map(|(certificate_path, private_key_path)| [certificate_path, private_key_path])
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect()
+}
+
+/// Reloads the certificates affected by a change of the `changed` path.
+/// Certificates whose chain did not change are left alone.
+fn reload_certificates(config: &ArcSwap<RuntimeConfig>, changed: &Path) {
+    let current = config.load();
+    for (certificate_name, (certificate_path, private_key_path)) in &current.certificate_files {
+        if !affected_by(certificate_path, changed) && !affected_by(private_key_path, changed) {
+            continue;
+        }
+        match load_certified_key(certificate_path, private_key_path) {
+            Ok(certified_key) => {
+                let unchanged = current
+                    .certificates
+                    .get(certificate_name)
+                    .is_some_and(|it| it.cert == certified_key.cert);
+                if unchanged {
+                    continue;
+                }
+                config.rcu(|config| {
+                    let mut config = RuntimeConfig::clone(config);
+                    config
+                        .certificates
+                        .insert(certificate_name.clone(), certified_key.clone());
+                    config
+                });
+                info!("Reloaded certificate for '{}'", certificate_name);
+            }
+            Err(e) => warn!(
+                "Keeping the old certificate for '{}', could not load the new one due to: {}",
+                certificate_name, e
+            ),
+        }
     }
 }
 
+/// Whether `file` may have changed with `changed`. Symlinks (e.g. of
+/// Kubernetes secret volumes) may point elsewhere after any change in their
+/// directory.
+fn affected_by(file: &Path, changed: &Path) -> bool {
+    file == changed
+        || (file.parent() == changed.parent()
+            && fs::symlink_metadata(file).is_ok_and(|it| it.file_type().is_symlink()))
+}
+
 fn start_config_watcher<P>(path: P) -> watch::Receiver<DebouncedEvent>
 where
     P: AsRef<Path> + Send + 'static,
@@ -119,9 +229,28 @@ fn watch_config_blocking<P: AsRef<Path>>(
     }
 }
 
+/// Keeps the OCSP staples of certificates which are unchanged by a reload, so
+/// that they are not served without a staple until the next OCSP check.
+fn carry_over_ocsp_staples(config: &mut RuntimeConfig, previous: &RuntimeConfig) {
+    for (name, certified_key) in &mut config.certificates {
+        if certified_key.ocsp.is_some() || !config.ocsp_sources.contains_key(name) {
+            continue;
+        }
+        let Some(leaf) = certified_key.cert.first() else {
+            continue;
+        };
+        certified_key.ocsp = previous
+            .certificates
+            .values()
+            .filter(|it| it.cert.first() == Some(leaf))
+            .find_map(|it| it.ocsp.clone());
+    }
+}
+
 async fn read_runtime_config<P>(
     path: P,
     acme_handler: Arc<AcmeHandler>,
+    previous_pools: &[Arc<BackendPool>],
     init_acme: bool,
 ) -> Result<RuntimeConfig, io::Error>
 where
@@ -135,104 +264,337 @@ where
             "Config path does not have a parrent",
         )
     })?;
-    runtime_config_from_toml_config(config_dir, config, acme_handler, init_acme).await
+    runtime_config_from_toml_config(config_dir, config, acme_handler, previous_pools, init_acme)
+        .await
 }
 
 async fn runtime_config_from_toml_config<P: AsRef<Path>>(
     config_dir: P,
     other: TomlConfig,
     acme_handler: Arc<AcmeHandler>,
+    previous_pools: &[Arc<BackendPool>],
     init_acme: bool,
 ) -> Result<RuntimeConfig, io::Error> {
-    let http_address = other.http_address.parse().map_err(invalid_data)?;
-    let https_address = other.https_address.parse().map_err(invalid_data)?;
+    let listeners = listeners_from_toml_config(&config_dir, &other)?;
+    for pool in &other.backend_pools {
+        for name in pool.listeners.iter().flatten() {
+            if !listeners.iter().any(|listener| &listener.name == name) {
+                return Err(invalid_data(format!(
+                    "Backend pool '{}' uses unknown listener '{}'",
+                    pool.matcher, name
+                )));
+            }
+        }
+    }
 
+    // pools without their own health check interval fall back to the global one
+    let default_health_interval = other.health_interval.check_every * 1000;
     let backend_pools = other
         .backend_pools
         .into_iter()
-        .map(|it| Arc::new(it.into()))
-        .collect();
+        .map(|mut it| {
+            it.health_config
+                .interval
+                .get_or_insert(default_health_interval);
+            it.into_backend_pool(&config_dir, previous_pools)
+                .map(Arc::new)
+        })
+        .collect::<Result<_, _>>()?;
 
     let mut certificates = HashMap::new();
+    let mut certificate_files = HashMap::new();
+    let mut acme_certificates = HashMap::new();
+    let mut ocsp_sources = HashMap::new();
+    // the names served by a certificate, including ACME alt_names
+    let mut configured_names = HashSet::new();
     for (sni_name, certificate_config) in other.certificates {
-        let server_name: ServerName = sni_name.as_str().try_into().map_err(invalid_data)?;
-        if init_acme || !matches!(certificate_config, CertificateConfig::ACME { .. }) {
-            let certificate =
-                create_certified_key(&config_dir, certificate_config, &server_name, &acme_handler)
+        let certificate_name = CertificateName::try_from(sni_name.as_str())?;
+        let mut insert_name = |name: &CertificateName| {
+            if configured_names.insert(name.clone()) {
+                Ok(())
+            } else {
+                Err(invalid_data(format!(
+                    "Certificate for '{}' configured more than once",
+                    name
+                )))
+            }
+        };
+        match certificate_config {
+            CertificateConfig::Local {
+                certificate_path,
+                private_key_path,
+                ocsp,
+            } => {
+                let certificate_path = config_dir.as_ref().join(certificate_path);
+                let private_key_path = config_dir.as_ref().join(private_key_path);
+                insert_name(&certificate_name)?;
+                let certificate = load_certified_key(&certificate_path, &private_key_path)?;
+                match ocsp {
+                    Some(OcspConfig::Responder) => {
+                        ocsp_sources.insert(certificate_name.clone(), OcspSource::Responder);
+                    }
+                    Some(OcspConfig::File { path }) => {
+                        let path = match path {
+                            Some(path) => config_dir.as_ref().join(path),
+                            None => {
+                                let mut path = certificate_path.clone().into_os_string();
+                                path.push(".ocsp");
+                                PathBuf::from(path)
+                            }
+                        };
+                        ocsp_sources.insert(certificate_name.clone(), OcspSource::File(path));
+                    }
+                    None => {}
+                }
+                certificates.insert(certificate_name.clone(), certificate);
+                certificate_files.insert(certificate_name, (certificate_path, private_key_path));
+            }
+            CertificateConfig::ACME(_)
+                if matches!(certificate_name, CertificateName::Wildcard(_)) =>
+            {
+                return Err(invalid_data(format!(
+                    "Wildcard certificate '{}' can not be obtained via ACME, which requires the unsupported DNS-01 challenge",
+                    certificate_name
+                )));
+            }
+            CertificateConfig::ACME(acme_config) => {
+                let acme_certificate = acme_config.into_acme_certificate(&config_dir)?;
+                for it in acme_certificate.certificate_names(&certificate_name) {
+                    insert_name(&it)?;
+                }
+                if init_acme {
+                    let certificate = obtain_acme_certificate(
+                        &certificate_name,
+                        &acme_certificate,
+                        &acme_handler,
+                    )
                     .await?;
-            certificates.insert(server_name, certificate);
+                    for it in acme_certificate.certificate_names(&certificate_name) {
+                        certificates.insert(it, certificate.clone());
+                    }
+                }
+                acme_certificates.insert(certificate_name, acme_certificate);
+            }
         }
     }
-
-    let health_interval_config: HealthIntervalConfig = other.health_interval;
-    let health_interval = Duration::from_secs(health_interval_config.check_every);
-
+    let default_certificate = other
+        .default_certificate
+        .map(|name| {
+            let certificate_name = CertificateName::try_from(name.as_str())?;
+            if !configured_names.contains(&certificate_name) {
+                return Err(invalid_data(format!(
+                    "Unknown default_certificate '{}'",
+                    name
+                )));
+            }
+            Ok(certificate_name)
+        })
+        .transpose()?;
     Ok(RuntimeConfig {
-        http_address,
-        https_address,
+        listeners,
         shared_data: SharedData {
             backend_pools,
+            trusted_proxies: TrustedProxies::new(other.trusted_proxies),
             acme_handler,
+            client_certificate_headers: other.client_certificate_headers.try_into()?,
         },
         certificates,
-        health_interval,
+        certificate_files,
+        acme_certificates,
+        ocsp_sources,
+        default_certificate,
     })
 }
 
-async fn create_certified_key<P: AsRef<Path>>(
+/// The `[[listeners]]` of the config, or a listener named `http` on
+/// `http_address` and one named `https` on `https_address` if there are none.
+fn listeners_from_toml_config<P: AsRef<Path>>(
     config_dir: P,
-    config: CertificateConfig,
-    sni_name: &ServerName,
-    acme_handler: &AcmeHandler,
-) -> Result<CertifiedKey, io::Error> {
-    let certified_key = match config {
-        CertificateConfig::Local {
-            certificate_path,
-            private_key_path,
-        } => {
-            let certificate_path = config_dir.as_ref().join(certificate_path);
-            let private_key_path = config_dir.as_ref().join(private_key_path);
-            load_certified_key(certificate_path, private_key_path)?
+    config: &TomlConfig,
+) -> Result<Vec<Listener>, io::Error> {
+    if config.listeners.is_empty() {
+        return Ok(vec![
+            Listener {
+                name: "http".to_string(),
+                address: config.http_address.parse().map_err(invalid_data)?,
+                scheme: Scheme::HTTP,
+                certificates: None,
+                proxy_protocol: None,
+                alpn_protocols: Vec::new(),
+                client_auth: None,
+                tls_policy: TlsPolicy::default(),
+                session_resumption: SessionResumption::default(),
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_concurrent_handshakes: DEFAULT_MAX_CONCURRENT_HANDSHAKES,
+            },
+            Listener {
+                name: "https".to_string(),
+                address: config.https_address.parse().map_err(invalid_data)?,
+                scheme: Scheme::HTTPS,
+                certificates: None,
+                proxy_protocol: None,
+                alpn_protocols: default_alpn_protocols(),
+                client_auth: None,
+                tls_policy: TlsPolicy::default(),
+                session_resumption: SessionResumption::default(),
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_concurrent_handshakes: DEFAULT_MAX_CONCURRENT_HANDSHAKES,
+            },
+        ]);
+    }
+
+    let mut listeners: Vec<Listener> = Vec::new();
+    for listener_config in &config.listeners {
+        let name = &listener_config.name;
+        if listeners.iter().any(|listener| &listener.name == name) {
+            return Err(invalid_data(format!("Duplicate listener '{}'", name)));
+        }
+        let address = listener_config
+            .address
+            .parse()
+            .map_err(|e| invalid_data(format!("Invalid address of listener '{}': {}", name, e)))?;
+        let certificate_names = listener_config
+            .tls
+            .as_ref()
+            .and_then(|tls| tls.certificates.as_ref());
+        if listener_config.scheme == Scheme::HTTP && listener_config.tls.is_some() {
+            warn!("TLS settings of HTTP listener '{}' are ignored", name);
+        }
+        let certificates = match certificate_names {
+            Some(certificate_names) => {
+                let mut certificates = Vec::new();
+                for certificate_name in certificate_names {
+                    if !config.certificates.contains_key(certificate_name) {
+                        return Err(invalid_data(format!(
+                            "Listener '{}' uses unknown certificate '{}'",
+                            name, certificate_name
+                        )));
+                    }
+                    certificates.push(CertificateName::try_from(certificate_name.as_str())?);
+                }
+                Some(certificates)
+            }
+            None => None,
+        };
+        let alpn_protocols = match listener_config.scheme {
+            Scheme::HTTP => Vec::new(),
+            Scheme::HTTPS => listener_config
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.alpn_protocols.clone())
+                .unwrap_or_else(default_alpn_protocols),
+        };
+        if let Some(protocol) = alpn_protocols
+            .iter()
+            .find(|protocol| !SUPPORTED_ALPN_PROTOCOLS.contains(&protocol.as_str()))
+        {
+            return Err(invalid_data(format!(
+                "Listener '{}' offers unsupported ALPN protocol '{}'",
+                name, protocol
+            )));
         }
-        CertificateConfig::ACME {
-            staging,
-            email,
-            persist_dir,
-        } => {
-            let persist_dir = config_dir.as_ref().join(persist_dir);
-            // TODO refresh certificates once they expire?
-            let sni_str = match sni_name {
-                ServerName::DnsName(d) => d.as_ref(),
-                ServerName::IpAddress(_) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "ACME does not support IP addresses",
-                    ));
+        let client_auth = match listener_config.scheme {
+            Scheme::HTTP => None,
+            Scheme::HTTPS => listener_config
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.client_auth.as_ref())
+                .map(|client_auth| {
+                    ClientAuth::load(
+                        config_dir.as_ref().join(&client_auth.ca_bundle_path),
+                        client_auth.required,
+                    )
+                })
+                .transpose()?,
+        };
+        let tls_policy = match (listener_config.scheme, &listener_config.tls) {
+            (Scheme::HTTPS, Some(tls)) => TlsPolicy::new(
+                tls.min_version.as_deref(),
+                tls.max_version.as_deref(),
+                tls.cipher_suites.as_deref(),
+                tls.kx_groups.as_deref(),
+            )
+            .map_err(|e| {
+                invalid_data(format!("Invalid TLS policy of listener '{}': {}", name, e))
+            })?,
+            _ => TlsPolicy::default(),
+        };
+        let session_resumption = match (listener_config.scheme, &listener_config.tls) {
+            (Scheme::HTTPS, Some(tls)) => {
+                let ticket_key_path = tls
+                    .session_ticket_key_path
+                    .as_ref()
+                    .map(|path| config_dir.as_ref().join(path));
+                if let Some(path) = &ticket_key_path {
+                    // fail on load, rather than with the first handshake
+                    load_ticket_keys(path)?;
                 }
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Unsupported server name type",
-                    ))
+                SessionResumption {
+                    tickets: tls.session_tickets.unwrap_or(true),
+                    ticket_key_path,
+                    cache_size: tls.session_cache_size.unwrap_or(DEFAULT_SESSION_CACHE_SIZE),
                 }
-            };
-            let certificate = acme_handler
-                .initiate_challenge(staging, &persist_dir, &email, sni_str)
-                .await
-                .map_err(other)?;
-
-            certified_key_from_acme_certificate(certificate).map_err(|e| {
-                io::Error::new(
-                    e.kind(),
-                    format!(
-                        "Could not load ACME certificate for '{}' due to: {}",
-                        sni_str, e
-                    ),
-                )
-            })?
+            }
+            _ => SessionResumption::default(),
+        };
+        listeners.push(Listener {
+            name: name.clone(),
+            address,
+            scheme: listener_config.scheme,
+            certificates,
+            proxy_protocol: listener_config
+                .proxy_protocol
+                .as_ref()
+                .map(|it| TrustedProxies::new(it.trusted_sources.clone())),
+            alpn_protocols,
+            client_auth,
+            tls_policy,
+            session_resumption,
+            handshake_timeout: listener_config
+                .handshake_timeout
+                .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+            max_concurrent_handshakes: listener_config
+                .max_concurrent_handshakes
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_HANDSHAKES),
+        });
+    }
+    Ok(listeners)
+}
+
+/// The cached certificate, or a new one if it expired. Once obtained, the
+/// certificate is renewed by [`acme::renew_certificates`](crate::acme::renew_certificates).
+async fn obtain_acme_certificate(
+    certificate_name: &CertificateName,
+    settings: &AcmeCertificate,
+    acme_handler: &AcmeHandler,
+) -> Result<CertifiedKey, io::Error> {
+    let sni_str = match certificate_name {
+        CertificateName::Exact(ServerName::DnsName(d)) => d.as_ref(),
+        CertificateName::Exact(ServerName::IpAddress(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ACME does not support IP addresses",
+            ));
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unsupported server name type",
+            ))
         }
     };
-    Ok(certified_key)
+    acme_handler
+        .initiate_challenge(settings, sni_str, 0)
+        .await
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Could not load ACME certificate for '{}' due to: {}",
+                    sni_str, e
+                ),
+            )
+        })
 }
 
 fn map_notify_error(error: notify::Error) -> io::Error {
@@ -262,33 +624,151 @@ where
     io::Error::new(io::ErrorKind::NotFound, error)
 }
 
-fn other<E>(error: E) -> io::Error
-where
-    E: Into<Box<dyn Error + Send + Sync>>,
-{
-    io::Error::other(error)
-}
-
+#[derive(Clone)]
 pub struct RuntimeConfig {
-    pub http_address: SocketAddr,
-    pub https_address: SocketAddr,
+    pub listeners: Vec<Listener>,
     pub shared_data: SharedData,
-    pub certificates: HashMap<ServerName, CertifiedKey>,
-    pub health_interval: Duration,
+    pub certificates: HashMap<CertificateName, CertifiedKey>,
+    /// The certificate and private key files of the `Local` certificates,
+    /// which are reloaded when they change.
+    pub certificate_files: HashMap<CertificateName, (PathBuf, PathBuf)>,
+    /// The `ACME` certificates, which are renewed before they expire.
+    pub acme_certificates: HashMap<CertificateName, AcmeCertificate>,
+    /// The certificates with stapled OCSP responses.
+    pub ocsp_sources: HashMap<CertificateName, OcspSource>,
+    /// Offered to clients without SNI or with a name no certificate matches
+    pub default_certificate: Option<CertificateName>,
+}
+
+impl RuntimeConfig {
+    pub fn listener(&self, name: &str) -> Option<&Listener> {
+        self.listeners.iter().find(|listener| listener.name == name)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct TomlConfig {
+    /// Only used if there are no `listeners`.
     #[serde(default = "default_http_address")]
     http_address: String,
+    /// Only used if there are no `listeners`.
     #[serde(default = "default_https_address")]
     https_address: String,
     #[serde(default)]
+    listeners: Vec<ListenerConfig>,
+    #[serde(default)]
     backend_pools: Vec<BackendPoolConfig>,
     #[serde(default)]
     certificates: HashMap<String, CertificateConfig>,
+    /// The name of the certificate offered if no other one matches the SNI
+    default_certificate: Option<String>,
+    /// The headers verified client certificates are passed to backends in
+    #[serde(default)]
+    client_certificate_headers: ClientCertificateHeadersConfig,
     #[serde(default = "default_health_interval_config")]
     health_interval: HealthIntervalConfig,
+    /// Proxies in front of RustStrom, whose forwarding headers are trusted to
+    /// contain the real client address, e.g. `["10.0.0.0/8"]`.
+    #[serde(default)]
+    trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ClientCertificateHeadersConfig {
+    subject: Option<String>,
+    alt_names: Option<String>,
+    fingerprint: Option<String>,
+}
+
+impl TryFrom<ClientCertificateHeadersConfig> for ClientCertificateHeaders {
+    type Error = io::Error;
+
+    fn try_from(other: ClientCertificateHeadersConfig) -> Result<Self, Self::Error> {
+        let header_name = |name: Option<String>, default| match name {
+            Some(name) => HeaderName::try_from(name.as_str()).map_err(|_| {
+                invalid_data(format!(
+                    "Invalid header name '{}' in client_certificate_headers",
+                    name
+                ))
+            }),
+            None => Ok(default),
+        };
+        let defaults = ClientCertificateHeaders::default();
+        Ok(ClientCertificateHeaders {
+            subject: header_name(other.subject, defaults.subject)?,
+            alt_names: header_name(other.alt_names, defaults.alt_names)?,
+            fingerprint: header_name(other.fingerprint, defaults.fingerprint)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListenerConfig {
+    name: String,
+    address: String,
+    scheme: Scheme,
+    tls: Option<ListenerTlsConfig>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    handshake_timeout: Option<Duration>,
+    max_concurrent_handshakes: Option<usize>,
+}
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CONCURRENT_HANDSHAKES: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+struct ProxyProtocolConfig {
+    /// Only connections from these networks must and may send a PROXY
+    /// protocol header, e.g. `["10.0.0.0/8"]`.
+    trusted_sources: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListenerTlsConfig {
+    /// Names of the `[certificates]` offered on the listener, all if absent.
+    certificates: Option<Vec<String>>,
+    /// The protocols offered via ALPN, `h2` and `http/1.1` if absent.
+    alpn_protocols: Option<Vec<String>>,
+    /// Verify client certificates (mutual TLS), not requested if absent.
+    client_auth: Option<ClientAuthConfig>,
+    /// The oldest TLS version offered, `"1.2"` or `"1.3"`, 1.2 if absent.
+    min_version: Option<String>,
+    /// The newest TLS version offered, 1.3 if absent.
+    max_version: Option<String>,
+    /// The cipher suites offered, e.g. `"TLS13_AES_256_GCM_SHA384"`, all
+    /// supported ones if absent.
+    cipher_suites: Option<Vec<String>>,
+    /// The key exchange groups offered, e.g. `"X25519"`, all supported ones
+    /// if absent.
+    kx_groups: Option<Vec<String>>,
+    /// Issue session tickets, enabled if absent.
+    session_tickets: Option<bool>,
+    /// File with base64 encoded ticket keys shared by RustStrom instances,
+    /// the first one encrypts. Random keys rotated every 6 hours if absent.
+    session_ticket_key_path: Option<String>,
+    /// Sessions kept for resumption without tickets, 256 if absent.
+    session_cache_size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientAuthConfig {
+    /// PEM file with the CAs client certificates are verified against
+    ca_bundle_path: String,
+    /// Reject clients without a certificate, otherwise they are let through
+    /// without the client certificate headers
+    #[serde(default = "default_client_auth_required")]
+    required: bool,
+}
+
+fn default_client_auth_required() -> bool {
+    true
+}
+
+/// The ALPN protocols spoken by the hyper server.
+const SUPPORTED_ALPN_PROTOCOLS: [&str; 2] = ["h2", "http/1.1"];
+
+fn default_alpn_protocols() -> Vec<String> {
+    SUPPORTED_ALPN_PROTOCOLS.map(String::from).to_vec()
 }
 
 // Dual Stack if /proc/sys/net/ipv6/bindv6only has default value 0
@@ -356,12 +836,18 @@ struct BackendPoolConfig {
     matcher: String,
     addresses: Vec<String>,
     schemes: HashSet<Scheme>,
+    /// Names of the listeners this pool serves, all if absent.
+    listeners: Option<HashSet<String>>,
     client: Option<ClientConfig>,
     #[serde(default = "default_health_config")]
     health_config: HealthTomlConfig,
     strategy: LoadBalancingStrategyConfig,
     #[serde(default)]
     middlewares: Table,
+    retry: Option<RetryConfig>,
+    /// Answer requests without a verified client certificate with 403
+    #[serde(default)]
+    require_client_certificate: bool,
 }
 
 fn default_health_config() -> HealthTomlConfig {
@@ -369,32 +855,43 @@ fn default_health_config() -> HealthTomlConfig {
         slow_threshold: default_slow_threshold(),
         timeout: default_timeout(),
         path: default_path(),
+        interval: None,
+        jitter: 0,
     }
 }
 
-impl From<BackendPoolConfig> for BackendPool {
-    fn from(other: BackendPoolConfig) -> Self {
+impl BackendPoolConfig {
+    /// Converts this config into a [`BackendPool`], carrying over the state of
+    /// the pool with the same matcher in `previous_pools` if there is one.
+    fn into_backend_pool<P: AsRef<Path>>(
+        self,
+        config_dir: P,
+        previous_pools: &[Arc<BackendPool>],
+    ) -> Result<BackendPool, io::Error> {
         // TODO: This conversion can fail, should we use TryFrom or wrap this in some kind of error?
-        let matcher = other.matcher.into();
-        let addresses = other
-            .addresses
-            .into_iter()
-            .map(|address| (address, ArcSwap::from_pointee(Healthiness::Healthy)))
-            .collect();
-        let health_toml_config = other.health_config;
-        let strategy = other.strategy.into();
-        let chain = other.middlewares.into();
-        let schemes = other.schemes;
+        let matcher: BackendPoolMatcher = self.matcher.clone().into();
+        let previous = previous_pools
+            .iter()
+            .find(|pool| pool.matcher == matcher)
+            .cloned();
+        let addresses = self.addresses;
+        let health_toml_config = self.health_config;
+        let strategy = self.strategy;
+        let chain = self.middlewares.into();
+        let schemes = self.schemes;
+        let listeners = self.listeners;
 
         let health_config = HealthConfig {
             slow_threshold: health_toml_config.slow_threshold,
             timeout: health_toml_config.timeout,
             path: health_toml_config.path,
+            interval: health_toml_config.interval.unwrap_or_default(),
+            jitter: health_toml_config.jitter,
         };
 
         let mut builder =
             BackendPoolBuilder::new(matcher, addresses, health_config, strategy, chain, schemes);
-        if let Some(client) = other.client {
+        if let Some(client) = self.client {
             if let Some(pool_idle_timeout) = client.pool_idle_timeout {
                 builder.pool_idle_timeout(pool_idle_timeout);
             }
@@ -402,9 +899,67 @@ impl From<BackendPoolConfig> for BackendPool {
             if let Some(pool_max_idle_per_host) = client.pool_max_idle_per_host {
                 builder.pool_max_idle_per_host(pool_max_idle_per_host);
             }
+
+            if let Some(connect_timeout) = client.connect_timeout {
+                builder.connect_timeout(connect_timeout);
+            }
+
+            if client.send_proxy_protocol {
+                builder.send_proxy_protocol();
+            }
+
+            builder.protocol(client.protocol);
+            if let Some(http2) = client.http2 {
+                if client.protocol == BackendProtocol::Http1 {
+                    warn!(
+                        "HTTP/2 settings of pool '{}' are ignored, its protocol is http1",
+                        self.matcher
+                    );
+                }
+                builder.http2(http2.into());
+            }
+            if client.protocol == BackendProtocol::Auto && client.tls.is_none() {
+                warn!(
+                    "Pool '{}' uses HTTP/1.1, protocol auto requires TLS to negotiate HTTP/2",
+                    self.matcher
+                );
+            }
+
+            if let Some(tls) = client.tls {
+                let upstream_tls = tls.into_upstream_tls(&config_dir)?;
+                if upstream_tls.insecure_skip_verify {
+                    warn!(
+                        "Certificates of the backend servers of pool '{}' are not verified",
+                        self.matcher
+                    );
+                }
+                let tls_config = upstream_tls.client_config()?;
+                builder.upstream_tls(upstream_tls, tls_config);
+            }
+
+            let timeouts = client.timeouts.apply_to(Timeouts::default());
+            builder.timeouts(timeouts);
+            for timeout_override in client.timeout_overrides {
+                builder.timeout_override(TimeoutOverride {
+                    matcher: timeout_override.matcher.into(),
+                    timeouts: timeout_override.timeouts.apply_to(timeouts),
+                });
+            }
+        }
+        if let Some(listeners) = listeners {
+            builder.listeners(listeners);
+        }
+        if let Some(retry) = self.retry {
+            builder.retry_policy(retry.into());
+        }
+        if self.require_client_certificate {
+            builder.require_client_certificate();
+        }
+        if let Some(previous) = previous {
+            builder.previous(previous);
         }
 
-        builder.build()
+        Ok(builder.build())
     }
 }
 
@@ -412,10 +967,165 @@ impl From<BackendPoolConfig> for BackendPool {
 struct ClientConfig {
     pool_idle_timeout: Option<Duration>,
     pool_max_idle_per_host: Option<usize>,
+    connect_timeout: Option<Duration>,
+    tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    send_proxy_protocol: bool,
+    #[serde(default)]
+    protocol: BackendProtocol,
+    http2: Option<Http2Config>,
+    #[serde(flatten)]
+    timeouts: TimeoutsConfig,
+    #[serde(default)]
+    timeout_overrides: Vec<TimeoutOverrideConfig>,
+}
+
+/// Tuning of HTTP/2 connections to the backend servers, see [`Http2Settings`].
+#[derive(Debug, Deserialize)]
+struct Http2Config {
+    initial_stream_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    #[serde(default)]
+    adaptive_window: bool,
+    max_frame_size: Option<u32>,
+    keep_alive_interval: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    #[serde(default)]
+    keep_alive_while_idle: bool,
+}
+
+impl From<Http2Config> for Http2Settings {
+    fn from(other: Http2Config) -> Self {
+        Http2Settings {
+            initial_stream_window_size: other.initial_stream_window_size,
+            initial_connection_window_size: other.initial_connection_window_size,
+            adaptive_window: other.adaptive_window,
+            max_frame_size: other.max_frame_size,
+            keep_alive_interval: other.keep_alive_interval,
+            keep_alive_timeout: other.keep_alive_timeout,
+            keep_alive_while_idle: other.keep_alive_while_idle,
+        }
+    }
+}
+
+/// Enables HTTPS to the backend servers of a pool, see [`UpstreamTls`].
+#[derive(Debug, Deserialize)]
+struct UpstreamTlsConfig {
+    ca_bundle_path: Option<String>,
+    server_name: Option<String>,
+    client_certificate_path: Option<String>,
+    client_private_key_path: Option<String>,
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+impl UpstreamTlsConfig {
+    /// Resolves the paths in this config relative to `config_dir`.
+    fn into_upstream_tls<P: AsRef<Path>>(self, config_dir: P) -> Result<UpstreamTls, io::Error> {
+        let config_dir = config_dir.as_ref();
+        let client_certificate = match (self.client_certificate_path, self.client_private_key_path)
+        {
+            (Some(certificate_path), Some(private_key_path)) => Some((
+                config_dir.join(certificate_path),
+                config_dir.join(private_key_path),
+            )),
+            (None, None) => None,
+            _ => {
+                return Err(invalid_data(
+                    "client_certificate_path and client_private_key_path must be set together",
+                ))
+            }
+        };
+        Ok(UpstreamTls {
+            ca_bundle_path: self.ca_bundle_path.map(|it| config_dir.join(it)),
+            server_name: self.server_name,
+            client_certificate,
+            insecure_skip_verify: self.insecure_skip_verify,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeoutsConfig {
+    response_header_timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+    idle_body_timeout: Option<Duration>,
+    upgrade_idle_timeout: Option<Duration>,
+}
+
+impl TimeoutsConfig {
+    /// Replaces the timeouts in `timeouts` which are set in this config. A
+    /// `total_timeout` without `response_header_timeout` also limits the time
+    /// until the response headers arrived, instead of the inherited one, so
+    /// that e.g. an override only raising `total_timeout` is not cut off by the
+    /// pool's `response_header_timeout`.
+    fn apply_to(&self, timeouts: Timeouts) -> Timeouts {
+        Timeouts {
+            response_header: self
+                .response_header_timeout
+                .or(self.total_timeout)
+                .or(timeouts.response_header),
+            total: self.total_timeout.or(timeouts.total),
+            idle_body: self.idle_body_timeout.or(timeouts.idle_body),
+            upgrade_idle: self.upgrade_idle_timeout.or(timeouts.upgrade_idle),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeoutOverrideConfig {
+    matcher: String,
+    #[serde(flatten)]
+    timeouts: TimeoutsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct RetryConfig {
+    #[serde(default = "default_max_attempts")]
+    max_attempts: usize,
+    #[serde(default = "default_retry_on")]
+    retry_on: Vec<RetryCondition>,
+    #[serde(default = "default_idempotent_only")]
+    idempotent_only: bool,
+    #[serde(default = "default_budget_ratio")]
+    budget_ratio: f64,
+    #[serde(default = "default_budget_burst")]
+    budget_burst: f64,
+}
+
+fn default_max_attempts() -> usize {
+    2
+}
+
+fn default_retry_on() -> Vec<RetryCondition> {
+    vec![RetryCondition::ConnectFailure]
+}
+
+fn default_idempotent_only() -> bool {
+    true
+}
+
+fn default_budget_ratio() -> f64 {
+    0.2
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-enum LoadBalancingStrategyConfig {
+fn default_budget_burst() -> f64 {
+    10.0
+}
+
+impl From<RetryConfig> for RetryPolicy {
+    fn from(other: RetryConfig) -> Self {
+        RetryPolicy {
+            max_attempts: other.max_attempts,
+            retry_on: other.retry_on,
+            idempotent_only: other.idempotent_only,
+            budget: RetryBudget::new(other.budget_ratio, other.budget_burst),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum LoadBalancingStrategyConfig {
     StickyCookie {
         cookie_name: String,
         http_only: bool,
@@ -456,7 +1166,7 @@ impl From<LoadBalancingStrategyConfig> for Box<dyn LoadBalancingStrategy> {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum StickyCookieSameSite {
     Strict,
     Lax,
@@ -538,13 +1248,104 @@ pub enum CertificateConfig {
     Local {
         certificate_path: String,
         private_key_path: String,
+        /// Staple OCSP responses, not stapled if absent
+        ocsp: Option<OcspConfig>,
     },
     #[allow(clippy::upper_case_acronyms)]
-    ACME {
-        staging: bool,
-        email: String,
-        persist_dir: String,
-    },
+    ACME(AcmeConfig),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum OcspConfig {
+    /// Fetch responses from the responder named in the certificate
+    Responder,
+    /// Read a DER encoded response, `<certificate_path>.ocsp` if `path` is absent
+    File { path: Option<String> },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcmeConfig {
+    #[serde(default)]
+    staging: bool,
+    /// The ACME directory of the CA, overrides `staging`
+    directory_url: Option<String>,
+    /// PEM file with the CAs to trust for the directory, e.g. of Pebble or step-ca
+    ca_bundle_path: Option<String>,
+    email: String,
+    /// External Account Binding, required by e.g. ZeroSSL
+    eab: Option<EabConfig>,
+    /// "http-01" (default) or "tls-alpn-01"
+    #[serde(default)]
+    challenge: AcmeChallenge,
+    /// Further names to include in the certificate
+    #[serde(default)]
+    alt_names: Vec<String>,
+    /// "ecdsa-p256" (default), "ecdsa-p384", "rsa-2048" or "rsa-4096"
+    #[serde(default)]
+    key_type: KeyType,
+    persist_dir: String,
+    /// Renew the certificate once it is valid for this many days or less
+    #[serde(default = "default_renew_before_days")]
+    renew_before_days: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EabConfig {
+    key_id: String,
+    /// Base64url encoded, as issued by the CA
+    hmac_key: String,
+}
+
+impl AcmeConfig {
+    fn into_acme_certificate<P: AsRef<Path>>(
+        self,
+        config_dir: P,
+    ) -> Result<AcmeCertificate, io::Error> {
+        let directory_url = match self.directory_url {
+            Some(directory_url) => directory_url,
+            None if self.staging => LETS_ENCRYPT_STAGING_DIRECTORY_URL.to_string(),
+            None => LETS_ENCRYPT_DIRECTORY_URL.to_string(),
+        };
+        let external_account = self
+            .eab
+            .map(|eab| {
+                // CAs hand out the key with or without padding
+                let hmac_key = URL_SAFE_NO_PAD
+                    .decode(eab.hmac_key.trim_end_matches('='))
+                    .map_err(|e| invalid_data(format!("Invalid eab.hmac_key: {}", e)))?;
+                Ok::<_, io::Error>(ExternalAccountBinding {
+                    key_id: eab.key_id,
+                    hmac_key,
+                })
+            })
+            .transpose()?;
+        let alt_names = self
+            .alt_names
+            .iter()
+            .map(|name| match ServerName::try_from(name.as_str()) {
+                Ok(server_name @ ServerName::DnsName(_)) => Ok(server_name),
+                _ => Err(invalid_data(format!("Invalid alt_names entry '{}'", name))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AcmeCertificate {
+            alt_names,
+            key_type: self.key_type,
+            directory_url,
+            ca_bundle_path: self
+                .ca_bundle_path
+                .map(|path| config_dir.as_ref().join(path)),
+            email: self.email,
+            external_account,
+            challenge: self.challenge,
+            persist_dir: config_dir.as_ref().join(self.persist_dir),
+            renew_before_days: self.renew_before_days,
+        })
+    }
+}
+
+fn default_renew_before_days() -> i64 {
+    30
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -560,6 +1361,11 @@ pub struct HealthTomlConfig {
     pub timeout: u64,
     #[serde(default = "default_path")]
     pub path: String,
+    /// Milliseconds between two probes of the same backend, defaults to `health_interval.check_every`
+    pub interval: Option<u64>,
+    /// Upper bound in milliseconds of the random delay added to every probe
+    #[serde(default)]
+    pub jitter: u64,
 }
 
 fn default_slow_threshold() -> i64 {
@@ -573,3 +1379,277 @@ fn default_timeout() -> u64 {
 fn default_path() -> String {
     "/".to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::{self_signed, write_self_signed};
+
+    #[test]
+    fn reads_acme_directory_and_external_account_binding() {
+        let config: CertificateConfig = toml::from_str(
+            r#"
+            type = "ACME"
+            directory_url = "https://acme.zerossl.com/v2/DV90"
+            email = "admin@example.com"
+            persist_dir = "acme"
+            eab = { key_id = "kid-1", hmac_key = "c2VjcmV0LWtleQ==" }
+            "#,
+        )
+        .unwrap();
+        let CertificateConfig::ACME(acme_config) = config else {
+            panic!("not an ACME certificate");
+        };
+        let acme_certificate = acme_config
+            .into_acme_certificate("/etc/rust-strom")
+            .unwrap();
+
+        assert_eq!(
+            acme_certificate.directory_url,
+            "https://acme.zerossl.com/v2/DV90"
+        );
+        assert_eq!(
+            acme_certificate.external_account,
+            Some(ExternalAccountBinding {
+                key_id: "kid-1".to_string(),
+                hmac_key: b"secret-key".to_vec(),
+            })
+        );
+        assert_eq!(
+            acme_certificate.persist_dir,
+            PathBuf::from("/etc/rust-strom/acme")
+        );
+        assert_eq!(acme_certificate.renew_before_days, 30);
+
+        let config: CertificateConfig = toml::from_str(
+            r#"
+            type = "ACME"
+            staging = true
+            email = "admin@example.com"
+            persist_dir = "acme"
+            "#,
+        )
+        .unwrap();
+        let CertificateConfig::ACME(acme_config) = config else {
+            panic!("not an ACME certificate");
+        };
+        assert_eq!(
+            acme_config
+                .into_acme_certificate("/")
+                .unwrap()
+                .directory_url,
+            LETS_ENCRYPT_STAGING_DIRECTORY_URL
+        );
+    }
+
+    #[test]
+    fn reads_acme_alt_names_and_key_type() {
+        let acme_config = |toml: &str| {
+            let config: CertificateConfig = toml::from_str(toml).unwrap();
+            let CertificateConfig::ACME(acme_config) = config else {
+                panic!("not an ACME certificate");
+            };
+            acme_config.into_acme_certificate("/")
+        };
+
+        let acme_certificate = acme_config(
+            r#"
+            type = "ACME"
+            email = "admin@example.com"
+            persist_dir = "acme"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(acme_certificate.key_type, KeyType::EcdsaP256);
+        assert!(acme_certificate.alt_names.is_empty());
+
+        let acme_certificate = acme_config(
+            r#"
+            type = "ACME"
+            email = "admin@example.com"
+            persist_dir = "acme"
+            alt_names = ["www.example.com"]
+            key_type = "rsa-2048"
+            "#,
+        )
+        .unwrap();
+        let primary_name = CertificateName::try_from("example.com").unwrap();
+        assert_eq!(acme_certificate.key_type, KeyType::Rsa2048);
+        assert_eq!(
+            acme_certificate.certificate_names(&primary_name),
+            vec![primary_name, "www.example.com".try_into().unwrap()]
+        );
+
+        assert!(acme_config(
+            r#"
+            type = "ACME"
+            email = "admin@example.com"
+            persist_dir = "acme"
+            alt_names = ["10.0.0.1"]
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn limits_response_headers_by_the_total_timeout_of_an_override() {
+        let timeouts = |toml: &str| toml::from_str::<TimeoutsConfig>(toml).unwrap();
+        let pool = timeouts("response_header_timeout = { secs = 2, nanos = 0 }")
+            .apply_to(Timeouts::default());
+
+        let only_total = timeouts("total_timeout = { secs = 600, nanos = 0 }").apply_to(pool);
+        assert_eq!(only_total.response_header, Some(Duration::from_secs(600)));
+        assert_eq!(only_total.total, Some(Duration::from_secs(600)));
+
+        let both = timeouts(
+            r#"
+            response_header_timeout = { secs = 300, nanos = 0 }
+            total_timeout = { secs = 600, nanos = 0 }
+            "#,
+        )
+        .apply_to(pool);
+        assert_eq!(both.response_header, Some(Duration::from_secs(300)));
+
+        let only_idle = timeouts("idle_body_timeout = { secs = 5, nanos = 0 }").apply_to(pool);
+        assert_eq!(only_idle.response_header, Some(Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn reads_ocsp_sources() {
+        let (certificate_path, private_key_path) = write_self_signed("localhost");
+        let toml = format!(
+            r#"
+            [certificates."localhost"]
+            type = "Local"
+            certificate_path = "{}"
+            private_key_path = "{}"
+            ocsp = {{ source = "file" }}
+
+            [certificates."example.com"]
+            type = "Local"
+            certificate_path = "{0}"
+            private_key_path = "{1}"
+            ocsp = {{ source = "responder" }}
+
+            [certificates."www.example.com"]
+            type = "Local"
+            certificate_path = "{0}"
+            private_key_path = "{1}"
+            "#,
+            certificate_path.display(),
+            private_key_path.display()
+        );
+        let config = runtime_config_from_toml_config(
+            "/",
+            toml::from_str(&toml).unwrap(),
+            Arc::new(AcmeHandler::new()),
+            &[],
+            false,
+        )
+        .await
+        .unwrap();
+        let mut response_path = certificate_path.into_os_string();
+        response_path.push(".ocsp");
+        assert_eq!(
+            config.ocsp_sources,
+            HashMap::from([
+                (
+                    CertificateName::try_from("localhost").unwrap(),
+                    OcspSource::File(response_path.into())
+                ),
+                (
+                    CertificateName::try_from("example.com").unwrap(),
+                    OcspSource::Responder
+                ),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_ocsp_staples_of_unchanged_certificates_on_reload() {
+        let (certificate_path, private_key_path) = write_self_signed("localhost");
+        let (other_certificate_path, other_private_key_path) = write_self_signed("example.com");
+        let toml = format!(
+            r#"
+            [certificates."localhost"]
+            type = "Local"
+            certificate_path = "{}"
+            private_key_path = "{}"
+            ocsp = {{ source = "responder" }}
+
+            [certificates."example.com"]
+            type = "Local"
+            certificate_path = "{}"
+            private_key_path = "{}"
+            ocsp = {{ source = "responder" }}
+            "#,
+            certificate_path.display(),
+            private_key_path.display(),
+            other_certificate_path.display(),
+            other_private_key_path.display()
+        );
+        let read_config = || async {
+            runtime_config_from_toml_config(
+                "/",
+                toml::from_str(&toml).unwrap(),
+                Arc::new(AcmeHandler::new()),
+                &[],
+                false,
+            )
+            .await
+            .unwrap()
+        };
+        let localhost = CertificateName::try_from("localhost").unwrap();
+        let example = CertificateName::try_from("example.com").unwrap();
+        let mut previous = read_config().await;
+        for certified_key in previous.certificates.values_mut() {
+            certified_key.ocsp = Some(b"staple".to_vec());
+        }
+        let (certificate, private_key) = self_signed("example.com");
+        fs::write(&other_certificate_path, certificate).unwrap();
+        fs::write(&other_private_key_path, private_key).unwrap();
+
+        let mut config = read_config().await;
+        carry_over_ocsp_staples(&mut config, &previous);
+
+        assert_eq!(
+            config.certificates[&localhost].ocsp,
+            Some(b"staple".to_vec())
+        );
+        assert_eq!(config.certificates[&example].ocsp, None);
+    }
+
+    #[test]
+    fn reloads_changed_certificates_unless_invalid() {
+        let (certificate_path, private_key_path) = write_self_signed("localhost");
+        let server_name = CertificateName::try_from("localhost").unwrap();
+        let certified_key = load_certified_key(&certificate_path, &private_key_path).unwrap();
+        let config = ArcSwap::from_pointee(RuntimeConfig {
+            listeners: Vec::new(),
+            shared_data: SharedData {
+                backend_pools: Vec::new(),
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            },
+            certificates: HashMap::from([(server_name.clone(), certified_key.clone())]),
+            certificate_files: HashMap::from([(
+                server_name.clone(),
+                (certificate_path.clone(), private_key_path.clone()),
+            )]),
+            acme_certificates: HashMap::new(),
+            ocsp_sources: HashMap::new(),
+            default_certificate: None,
+        });
+        let (certificate, private_key) = self_signed("localhost");
+
+        // the key does not belong to the new certificate yet
+        fs::write(&certificate_path, &certificate).unwrap();
+        reload_certificates(&config, &certificate_path);
+        assert!(config.load().certificates[&server_name].cert == certified_key.cert);
+
+        fs::write(&private_key_path, &private_key).unwrap();
+        reload_certificates(&config, &private_key_path);
+        assert!(config.load().certificates[&server_name].cert != certified_key.cert);
+    }
+}
@@ -1,6 +1,7 @@
+use crate::timeouts::TimeoutKind;
 use hyper::{Body, Response, StatusCode};
 use log::error;
-use std::error::Error;
+use std::{error::Error, io};
 
 pub fn not_found() -> Response<Body> {
     Response::builder()
@@ -9,6 +10,44 @@ pub fn not_found() -> Response<Body> {
         .unwrap()
 }
 
+/// Attached as extension to responses generated because a backend could not be
+/// reached, to tell them apart from error responses sent by the backend itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendFailure {
+    Connect,
+    Request,
+    Timeout,
+}
+
+pub fn handle_backend_error(error: hyper::Error) -> Response<Body> {
+    let mut response = if error.is_connect() && is_timeout(&error) {
+        log_error(&error);
+        gateway_timeout(TimeoutKind::Connect)
+    } else {
+        handle_bad_gateway(&error)
+    };
+    let failure = if error.is_connect() {
+        BackendFailure::Connect
+    } else {
+        BackendFailure::Request
+    };
+    response.extensions_mut().insert(failure);
+    response
+}
+
+fn is_timeout(error: &dyn Error) -> bool {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+            if io_error.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = cause.source();
+    }
+    false
+}
+
 pub fn handle_bad_gateway<E: Error>(error: E) -> Response<Body> {
     log_error(error);
     bad_gateway()
@@ -31,6 +70,16 @@ where
         .unwrap()
 }
 
+pub fn forbidden<B>(message: B) -> Response<Body>
+where
+    Body: From<B>,
+{
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from(message))
+        .unwrap()
+}
+
 pub fn handle_internal_server_error<E: Error>(error: E) -> Response<Body> {
     log_error(error);
     internal_server_error()
@@ -61,9 +110,12 @@ pub fn too_many_requests() -> Response<Body> {
         .unwrap()
 }
 
-pub fn gateway_timeout() -> Response<Body> {
+pub fn gateway_timeout(timeout: TimeoutKind) -> Response<Body> {
     Response::builder()
         .status(StatusCode::GATEWAY_TIMEOUT)
-        .body(Body::from("504 - Gateway Timeout"))
+        .body(Body::from(format!(
+            "504 - Gateway Timeout ({} exceeded)",
+            timeout
+        )))
         .unwrap()
 }
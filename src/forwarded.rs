@@ -0,0 +1,217 @@
+use crate::server::Scheme;
+use hyper::{header::HeaderValue, HeaderMap};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Headers in which proxies report the addresses of the clients they forward
+/// requests for.
+const FORWARDED_FOR_HEADERS: [&str; 2] = ["forwarded", "x-forwarded-for"];
+
+/// The proxies (e.g. a cloud load balancer in front of RustStrom) whose
+/// `Forwarded` and `X-Forwarded-For` headers are trusted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> TrustedProxies {
+        TrustedProxies(networks)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Resolves the address of the client that sent the request with `headers`
+    /// to `peer`. Walks the addresses reported in `Forwarded` (or
+    /// `X-Forwarded-For` if absent) from right to left as long as they belong
+    /// to trusted proxies. Returns `peer` if it is not trusted itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client_ip = peer;
+        if !self.contains(&peer) {
+            return client_ip;
+        }
+        let mut hops = forwarded_for(headers);
+        if hops.is_empty() {
+            hops = x_forwarded_for(headers);
+        }
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client_ip = ip;
+                    if !self.contains(&ip) {
+                        break;
+                    }
+                }
+                // obfuscated or unknown, nothing before it can be trusted
+                None => break,
+            }
+        }
+        client_ip
+    }
+
+    /// Removes the forwarding headers sent by `peer` unless it is trusted, so
+    /// that clients can not spoof their address.
+    pub fn sanitize(&self, peer: IpAddr, headers: &mut HeaderMap) {
+        if !self.contains(&peer) {
+            for name in FORWARDED_FOR_HEADERS {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// The `for` parameters of all elements in the `Forwarded` headers, see
+/// [RFC 7239](https://www.rfc-editor.org/rfc/rfc7239#section-4). Nodes which
+/// are not an IP address are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_elements(headers, "forwarded")
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_elements(headers, "x-forwarded-for")
+        .map(parse_node)
+        .collect()
+}
+
+fn header_elements<'h>(headers: &'h HeaderMap, name: &str) -> impl Iterator<Item = &'h str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|element| !element.is_empty())
+}
+
+/// Parses a node like `192.0.2.60`, `192.0.2.60:4711` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|it| it.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+/// Appends an element for the current hop to the `Forwarded` header.
+pub fn forwarded_header(
+    existing_forwarded: Option<&HeaderValue>,
+    peer: IpAddr,
+    scheme: &Scheme,
+    host: Option<&HeaderValue>,
+) -> String {
+    let mut element = match peer {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    element.push_str(&format!(";proto={}", scheme));
+    if let Some(host) = host.and_then(|it| it.to_str().ok()) {
+        element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+    }
+    match existing_forwarded.and_then(|it| it.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, element),
+        None => element,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> TrustedProxies {
+        TrustedProxies::new(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ])
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let headers = headers("x-forwarded-for", "203.0.113.7");
+
+        assert_eq!(
+            trusted().client_ip("198.51.100.1".parse().unwrap(), &headers),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn skips_trusted_proxies_in_x_forwarded_for() {
+        let headers = headers("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2");
+
+        assert_eq!(
+            trusted().client_ip("10.0.0.1".parse().unwrap(), &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn prefers_forwarded_header() {
+        let mut headers = headers(
+            "forwarded",
+            "for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+        );
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+
+        assert_eq!(
+            trusted().client_ip("10.0.0.1".parse().unwrap(), &headers),
+            "192.0.2.43".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn stops_at_obfuscated_nodes() {
+        let headers = headers("forwarded", "for=192.0.2.43, for=_hidden, for=10.0.0.3");
+
+        assert_eq!(
+            trusted().client_ip("10.0.0.1".parse().unwrap(), &headers),
+            "10.0.0.3".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn sanitize_removes_headers_of_untrusted_peers() {
+        let mut headers = headers("x-forwarded-for", "203.0.113.7");
+        headers.insert("forwarded", HeaderValue::from_static("for=203.0.113.7"));
+
+        trusted().sanitize("10.0.0.1".parse().unwrap(), &mut headers);
+        assert_eq!(headers.len(), 2);
+
+        trusted().sanitize("198.51.100.1".parse().unwrap(), &mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn formats_forwarded_elements() {
+        let existing = HeaderValue::from_static("for=192.0.2.43");
+        let host = HeaderValue::from_static("example.com");
+
+        assert_eq!(
+            forwarded_header(
+                Some(&existing),
+                "2001:db8::1".parse().unwrap(),
+                &Scheme::HTTPS,
+                Some(&host)
+            ),
+            "for=192.0.2.43, for=\"[2001:db8::1]\";proto=https;host=\"example.com\""
+        );
+        assert_eq!(
+            forwarded_header(None, "10.0.0.1".parse().unwrap(), &Scheme::HTTP, None),
+            "for=10.0.0.1;proto=http"
+        );
+    }
+}
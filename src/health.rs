@@ -1,6 +1,10 @@
-use crate::server::BackendPool;
+use crate::{
+    http_client::{https_connector, BackendProtocol},
+    metrics,
+    server::{BackendPool, ClientSettings},
+    tls::plain_client_config,
+};
 use arc_swap::{access::Access, ArcSwap};
-use futures::future::join_all;
 use hyper::{
     client::HttpConnector,
     http::uri::{self, Authority},
@@ -8,18 +12,25 @@ use hyper::{
 };
 use hyper_timeout::TimeoutConnector;
 use log::info;
+use rand::{thread_rng, Rng};
 use serde::Deserialize;
-use std::time::Duration;
 use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use std::{convert::TryFrom, ops::Deref};
-use std::{fmt, sync::Arc};
-use tokio::time::interval;
+use std::{error::Error, fmt, io, sync::Arc};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, sleep, MissedTickBehavior},
+};
+use tokio_rustls::rustls::ClientConfig;
 /* Contains the user preferences regarding health checks */
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct HealthConfig {
     pub slow_threshold: i64,
     pub timeout: u64,
     pub path: String,
+    pub interval: u64,
+    pub jitter: u64,
 }
 /* Healthiness of a backend server */
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -29,6 +40,17 @@ pub enum Healthiness {
     Unresponsive(Option<StatusCode>),
 }
 
+impl Healthiness {
+    /* Name of the state without details, used as metric label */
+    pub fn state_label(&self) -> &'static str {
+        match self {
+            Healthiness::Healthy => "healthy",
+            Healthiness::Slow(_) => "slow",
+            Healthiness::Unresponsive(_) => "unresponsive",
+        }
+    }
+}
+
 impl fmt::Display for Healthiness {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -41,91 +63,242 @@ impl fmt::Display for Healthiness {
         }
     }
 }
-/* Start loop to regularly contact backend to investigate the healthiness of each server.
+/* How often the probe schedule is compared against the loaded backend pools,
+so that probes of removed pools stop and probes of new pools start after a config reload */
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Start one probe task per backend server to regularly investigate its healthiness.
+Each task runs on its own timer, so slow probes of one pool don't delay the others.
 The healthiness is noted in the backend_pool vector  */
-pub async fn watch_health<A, G, H, J>(backend_pools: A, interval_duration: H)
+pub async fn watch_health<A, G>(backend_pools: A)
 where
     A: Access<Vec<Arc<BackendPool>>, Guard = G> + Send + Sync + 'static,
     G: Deref<Target = Vec<Arc<BackendPool>>> + Send + Sync,
-    H: Access<Duration, Guard = J>,
-    J: Deref<Target = Duration>,
 {
+    let mut probes: Vec<(Arc<BackendPool>, Vec<JoinHandle<()>>)> = Vec::new();
     loop {
-        // set and start interval timer
-        let interval_duration = *interval_duration.load().deref();
-        if interval_duration == Duration::from_secs(0) {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            continue;
+        let loaded_pools = backend_pools.load().deref().clone();
+        let (kept, removed): (Vec<_>, Vec<_>) = probes
+            .into_iter()
+            .partition(|(pool, _)| loaded_pools.iter().any(|it| Arc::ptr_eq(it, pool)));
+        probes = kept;
+        for (pool, handles) in removed {
+            handles.iter().for_each(JoinHandle::abort);
+            // wait until the aborted tasks can not report anymore
+            for handle in handles {
+                let _ = handle.await;
+            }
+            remove_metrics_of_removed_backends(&pool, &loaded_pools);
         }
-        let mut interval_timer = interval(interval_duration);
-        interval_timer.tick().await;
-        // create and perform server checks
-        let loaded_pools = backend_pools.load();
-        let mut checks = Vec::new();
         for pool in loaded_pools.iter() {
-            for (server_address, healthiness) in &pool.addresses {
-                let future = check_server_health_once(
-                    server_address.clone(),
-                    healthiness,
-                    &pool.health_config,
-                );
-                checks.push(future);
+            if !probes.iter().any(|(it, _)| Arc::ptr_eq(it, pool)) {
+                let handles = (0..pool.addresses.len())
+                    .map(|index| tokio::spawn(probe_periodically(pool.clone(), index)))
+                    .collect();
+                probes.push((pool.clone(), handles));
             }
         }
-        join_all(checks).await;
-        /*  Yes tick is called twice in one loop on purpose.
-        Since we are recreating interval_timer on every loop,
-        the first tick, marks the starting point, resuming immediately.
-        */
+        sleep(RECONCILE_INTERVAL).await;
+    }
+}
+/* Removes the metric series of the backends of a replaced pool which are not in any loaded pool.
+Those which are keep their series, the probes of the replacing pool report to them */
+fn remove_metrics_of_removed_backends(pool: &BackendPool, loaded_pools: &[Arc<BackendPool>]) {
+    let pool_label = pool.matcher.to_string();
+    for (backend, _) in &pool.addresses {
+        let still_probed = loaded_pools.iter().any(|it| {
+            it.health_config.interval != 0
+                && it.matcher.to_string() == pool_label
+                && it.addresses.iter().any(|(address, _)| address == backend)
+        });
+        if !still_probed {
+            let _ = metrics::BACKEND_UP.remove_label_values(&[&pool_label, backend]);
+            let _ = metrics::HEALTH_CHECK_DURATION.remove_label_values(&[&pool_label, backend]);
+        }
+    }
+}
+/* Probes a single server of the pool forever, waiting the pool's interval plus a random jitter between probes */
+async fn probe_periodically(pool: Arc<BackendPool>, index: usize) {
+    let health_config = &pool.health_config;
+    if health_config.interval == 0 {
+        return;
+    }
+    let (server_address, healthiness) = &pool.addresses[index];
+    let mut reported = ReportedHealth::new(pool.matcher.to_string(), server_address.clone());
+    let mut interval_timer = interval(Duration::from_millis(health_config.interval));
+    interval_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
         interval_timer.tick().await;
+        if health_config.jitter > 0 {
+            let jitter = thread_rng().gen_range(0..=health_config.jitter);
+            sleep(Duration::from_millis(jitter)).await;
+        }
+        check_server_health_once(
+            server_address.clone(),
+            healthiness,
+            health_config,
+            &pool,
+            &mut reported,
+        )
+        .await;
     }
 }
-/* Contacts one server and sets health value if changed */
+/* The health state a probe task last counted in backend_health_state. It is withdrawn once the
+task is aborted, e.g. because its pool was replaced by a config reload. The backend_up series
+are removed by watch_health instead, only if the backend is gone, so that an old task dropped
+after the replacing one reported does not remove the series of the backend */
+struct ReportedHealth {
+    pool: String,
+    backend: String,
+    state: Option<&'static str>,
+}
+
+impl ReportedHealth {
+    fn new(pool: String, backend: String) -> ReportedHealth {
+        ReportedHealth {
+            pool,
+            backend,
+            state: None,
+        }
+    }
+
+    fn report(&mut self, healthiness: &Healthiness) {
+        let state = healthiness.state_label();
+        if self.state != Some(state) {
+            if let Some(previous_state) = self.state {
+                metrics::BACKEND_HEALTH_STATE
+                    .with_label_values(&[previous_state])
+                    .dec();
+            }
+            metrics::BACKEND_HEALTH_STATE
+                .with_label_values(&[state])
+                .inc();
+            self.state = Some(state);
+        }
+        let up = !matches!(healthiness, Healthiness::Unresponsive(_));
+        metrics::BACKEND_UP
+            .with_label_values(&[&self.pool, &self.backend])
+            .set(up as i64);
+    }
+}
+
+impl Drop for ReportedHealth {
+    fn drop(&mut self) {
+        if let Some(state) = self.state {
+            metrics::BACKEND_HEALTH_STATE
+                .with_label_values(&[state])
+                .dec();
+        }
+    }
+}
+/* Contacts one server, sets health value if changed and exports the result as metrics */
 async fn check_server_health_once(
     server_address: String,
     healthiness: &ArcSwap<Healthiness>,
     health_config: &HealthConfig,
+    pool: &BackendPool,
+    reported: &mut ReportedHealth,
 ) {
     let uri = uri::Uri::builder()
-        .scheme("http")
+        .scheme(pool.backend_scheme().to_string().as_str())
         .path_and_query(&health_config.path)
         .authority(Authority::from_maybe_shared(server_address.clone()).unwrap())
         .build()
         .unwrap();
 
     let previous_healthiness = healthiness.load();
-    let result = contact_server(uri, health_config.slow_threshold, health_config.timeout).await;
+    let before_request = Instant::now();
+    let result = contact_server(
+        uri,
+        health_config.slow_threshold,
+        health_config.timeout,
+        &pool.client_settings,
+        pool.tls_config.as_deref(),
+    )
+    .await;
+    metrics::HEALTH_CHECK_DURATION
+        .with_label_values(&[&reported.pool, &server_address])
+        .observe(before_request.elapsed().as_secs_f64());
+    let result = match result {
+        Ok(healthiness) => {
+            if let Healthiness::Unresponsive(_) = healthiness {
+                metrics::HEALTH_CHECK_FAILURES
+                    .with_label_values(&["status"])
+                    .inc();
+            }
+            healthiness
+        }
+        Err(e) => {
+            metrics::HEALTH_CHECK_FAILURES
+                .with_label_values(&[failure_reason(&e)])
+                .inc();
+            Healthiness::Unresponsive(None)
+        }
+    };
+    reported.report(&result);
 
     if previous_healthiness.as_ref() != &result {
         info!("new healthiness for {}: {}", &server_address, &result);
         healthiness.store(Arc::new(result));
     }
 }
-/* Returns the healthiness of the given server by performing a network request  */
-async fn contact_server(server_address: Uri, slow_threshold: i64, timeout: u64) -> Healthiness {
-    let http_connector = HttpConnector::new();
-    let mut connector = TimeoutConnector::new(http_connector);
+/* Classifies why the request of a health check failed */
+fn failure_reason(error: &hyper::Error) -> &'static str {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+            if io_error.kind() == io::ErrorKind::TimedOut {
+                return "timeout";
+            }
+        }
+        source = cause.source();
+    }
+    if error.is_connect() {
+        "connect"
+    } else {
+        "request"
+    }
+}
+/* Returns the healthiness of the given server by performing a network request,
+via HTTPS and HTTP/2 if the pool contacts its servers that way */
+async fn contact_server(
+    server_address: Uri,
+    slow_threshold: i64,
+    timeout: u64,
+    client_settings: &ClientSettings,
+    tls_config: Option<&ClientConfig>,
+) -> Result<Healthiness, hyper::Error> {
+    let https_connector = https_connector(
+        HttpConnector::new(),
+        client_settings.protocol,
+        client_settings.send_proxy_protocol,
+        tls_config.cloned().unwrap_or_else(plain_client_config),
+        client_settings
+            .upstream_tls
+            .as_ref()
+            .and_then(|it| it.server_name.clone()),
+    );
+    let mut connector = TimeoutConnector::new(https_connector);
     connector.set_connect_timeout(Some(Duration::from_millis(timeout)));
     connector.set_read_timeout(Some(Duration::from_millis(timeout)));
     connector.set_write_timeout(Some(Duration::from_millis(timeout)));
-    let client = Client::builder().build::<_, hyper::Body>(connector);
+    let client = Client::builder()
+        .http2_only(client_settings.protocol == BackendProtocol::H2c)
+        .build::<_, hyper::Body>(connector);
 
     let before_request = SystemTime::now();
     // Await the response...
-    if let Ok(response) = client.get(server_address).await {
-        if response.status().is_success() {
-            // elapsed() only fails when system time is later than "self"
-            let time_to_respond = before_request.elapsed().unwrap().as_millis();
-            let response_time = i64::try_from(time_to_respond);
-            if response_time.unwrap() > slow_threshold {
-                Healthiness::Slow(response_time.unwrap())
-            } else {
-                Healthiness::Healthy
-            }
+    let response = client.get(server_address).await?;
+    if response.status().is_success() {
+        // elapsed() only fails when system time is later than "self"
+        let time_to_respond = before_request.elapsed().unwrap().as_millis();
+        let response_time = i64::try_from(time_to_respond);
+        if response_time.unwrap() > slow_threshold {
+            Ok(Healthiness::Slow(response_time.unwrap()))
         } else {
-            Healthiness::Unresponsive(Some(response.status()))
+            Ok(Healthiness::Healthy)
         }
     } else {
-        Healthiness::Unresponsive(None)
+        Ok(Healthiness::Unresponsive(Some(response.status())))
     }
 }
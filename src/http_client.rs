@@ -1,22 +1,28 @@
 use std::{
+    fmt::Debug,
     io,
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use crate::algorithms::LoadBalancingStrategy;
+use crate::{algorithms::LoadBalancingStrategy, proxy_protocol};
 use futures::Future;
 use hyper::{
-    client::{connect::Connection, HttpConnector},
+    client::{self, connect::Connection, HttpConnector},
     http::uri::Uri,
     service::Service,
 };
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder, MaybeHttpsStream};
 use pin_project::{pin_project, pinned_drop};
+use serde::Deserialize;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
+use tokio_rustls::rustls::ClientConfig;
 
 /// A wrapper around any async stream. Notifies the given strategy once the stream is closed
 #[pin_project(PinnedDrop)]
@@ -77,30 +83,175 @@ impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> Connection for Strate
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct StrategyNotifyHttpConnector {
+/// The HTTP version spoken with the backend servers of a pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 with prior knowledge, also without TLS. Upgrades like WebSocket
+    /// are not possible.
+    H2c,
+    /// HTTP/2 if the backend server chooses it via ALPN, HTTP/1.1 otherwise.
+    /// Connections without TLS always use HTTP/1.1.
+    Auto,
+}
+
+/// Tuning of HTTP/2 connections to the backend servers, unset values use the
+/// defaults of hyper. The number of concurrent streams per connection is
+/// limited by the `SETTINGS_MAX_CONCURRENT_STREAMS` of the backend server,
+/// requests exceeding it wait for a free stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Http2Settings {
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    /// Adjust the window sizes to the measured bandwidth-delay product, the
+    /// initial window sizes are ignored if enabled.
+    pub adaptive_window: bool,
+    pub max_frame_size: Option<u32>,
+    pub keep_alive_interval: Option<Duration>,
+    pub keep_alive_timeout: Option<Duration>,
+    pub keep_alive_while_idle: bool,
+}
+
+impl Http2Settings {
+    /// Configures the HTTP/2 connections of clients built with `builder`.
+    pub fn apply_to(&self, builder: &mut client::Builder) {
+        builder
+            .http2_initial_stream_window_size(self.initial_stream_window_size)
+            .http2_initial_connection_window_size(self.initial_connection_window_size)
+            .http2_adaptive_window(self.adaptive_window)
+            .http2_max_frame_size(self.max_frame_size)
+            .http2_keep_alive_interval(self.keep_alive_interval)
+            .http2_keep_alive_while_idle(self.keep_alive_while_idle);
+        if let Some(keep_alive_timeout) = self.keep_alive_timeout {
+            builder.http2_keep_alive_timeout(keep_alive_timeout);
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The address of the client a backend request is sent for, announced via
+    /// PROXY protocol on new connections to the backend server.
+    pub static PROXY_PROTOCOL_SOURCE: SocketAddr;
+}
+
+/// Connects via `inner` and sends a PROXY protocol v2 header announcing the
+/// [`PROXY_PROTOCOL_SOURCE`] if enabled. Connections opened outside of its
+/// scope (e.g. health checks) send a `LOCAL` header instead.
+#[derive(Clone)]
+pub struct ProxyProtocolConnector {
     inner: HttpConnector,
+    send_proxy_protocol: bool,
+}
+
+impl Service<Uri> for ProxyProtocolConnector {
+    type Response = TcpStream;
+
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Uri) -> Self::Future {
+        let connecting = self.inner.call(req);
+        let send_proxy_protocol = self.send_proxy_protocol;
+        // read here, the returned future may be polled outside of the scope
+        let source = PROXY_PROTOCOL_SOURCE.try_with(|source| *source).ok();
+
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if send_proxy_protocol {
+                let header = match source {
+                    Some(source) => proxy_protocol::encode_v2(source, stream.peer_addr()?),
+                    None => proxy_protocol::encode_v2_local(),
+                };
+                stream.write_all(&header).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/// Wraps `http` to connect to `https` URIs using `tls_config`. Plain `http`
+/// URIs are still supported. The SNI sent and verified is `server_name` if
+/// present or the host of the URI otherwise. The offered ALPN protocols follow
+/// `protocol`. Every new connection starts with a PROXY protocol header if
+/// `send_proxy_protocol` is set.
+pub fn https_connector(
+    mut http: HttpConnector,
+    protocol: BackendProtocol,
+    send_proxy_protocol: bool,
+    tls_config: ClientConfig,
+    server_name: Option<String>,
+) -> HttpsConnector<ProxyProtocolConnector> {
+    http.enforce_http(false);
+    let http = ProxyProtocolConnector {
+        inner: http,
+        send_proxy_protocol,
+    };
+    let mut builder = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http();
+    if let Some(server_name) = server_name {
+        builder = builder.with_server_name(server_name);
+    }
+    match protocol {
+        BackendProtocol::Http1 => builder.enable_http1().wrap_connector(http),
+        BackendProtocol::H2c => builder.enable_http2().wrap_connector(http),
+        BackendProtocol::Auto => builder.enable_all_versions().wrap_connector(http),
+    }
+}
+
+#[derive(Clone)]
+pub struct StrategyNotifyHttpConnector {
+    inner: HttpsConnector<ProxyProtocolConnector>,
     strategy: Arc<Box<dyn LoadBalancingStrategy>>,
 }
 
+impl Debug for StrategyNotifyHttpConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StrategyNotifyHttpConnector")
+            .field("strategy", &self.strategy)
+            .finish()
+    }
+}
+
 impl StrategyNotifyHttpConnector {
-    pub fn new(strategy: Arc<Box<dyn LoadBalancingStrategy>>) -> StrategyNotifyHttpConnector {
+    pub fn new(
+        strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+        connect_timeout: Option<Duration>,
+        protocol: BackendProtocol,
+        send_proxy_protocol: bool,
+        tls_config: ClientConfig,
+        server_name: Option<String>,
+    ) -> StrategyNotifyHttpConnector {
         let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(connect_timeout);
         // Performance optimization: Enable connection pooling and keep-alive
-        connector.set_keepalive(Some(std::time::Duration::from_secs(90)));
+        connector.set_keepalive(Some(Duration::from_secs(90)));
         connector.set_nodelay(true);
         // Allow more concurrent connections per host for better throughput
         connector.set_reuse_address(true);
 
         StrategyNotifyHttpConnector {
-            inner: connector,
+            inner: https_connector(
+                connector,
+                protocol,
+                send_proxy_protocol,
+                tls_config,
+                server_name,
+            ),
             strategy,
         }
     }
 }
 
 impl Service<Uri> for StrategyNotifyHttpConnector {
-    type Response = StrategyNotifyStream<TcpStream>;
+    type Response = StrategyNotifyStream<MaybeHttpsStream<TcpStream>>;
 
     type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -109,7 +260,7 @@ impl Service<Uri> for StrategyNotifyHttpConnector {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(|e| e.into())
+        self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: Uri) -> Self::Future {
@@ -122,7 +273,7 @@ impl Service<Uri> for StrategyNotifyHttpConnector {
                     self_.strategy.on_tcp_open(&req_);
                     Ok(StrategyNotifyStream::new(stream, req_, self_.strategy))
                 }
-                Err(e) => Err(e.into()),
+                Err(e) => Err(e),
             }
         })
     }
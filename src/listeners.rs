@@ -1,24 +1,93 @@
+use crate::{
+    configuration::RuntimeConfig,
+    forwarded::TrustedProxies,
+    metrics, proxy_protocol,
+    server::Scheme,
+    tls::{self, CertificateName, ClientAuth, ClientCertificate, SessionResumption, TlsPolicy},
+};
+use arc_swap::ArcSwap;
 use async_stream::stream;
 use async_trait::async_trait;
-use futures::Stream;
-use log::{error, info};
+use futures::{future::Either, Future, Stream};
+use log::{debug, info};
 use std::{
+    fmt::{self, Display},
     io,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify},
+    time::timeout,
 };
-use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::server::TlsStream;
-use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_rustls::TlsAcceptor;
+
+/// A named address on which RustStrom accepts requests of one [`Scheme`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub name: String,
+    pub address: SocketAddr,
+    pub scheme: Scheme,
+    /// The certificates offered on an HTTPS listener, `None` offers all.
+    pub certificates: Option<Vec<CertificateName>>,
+    /// Read a PROXY protocol header from connections of these sources, e.g. a
+    /// load balancer in front of RustStrom. `None` disables PROXY protocol.
+    pub proxy_protocol: Option<TrustedProxies>,
+    /// The protocols offered via ALPN on an HTTPS listener, in order of
+    /// preference, e.g. `h2` and `http/1.1`.
+    pub alpn_protocols: Vec<String>,
+    /// Verify client certificates on an HTTPS listener, `None` does not ask
+    /// clients for one.
+    pub client_auth: Option<ClientAuth>,
+    /// The TLS versions, cipher suites and key exchange groups offered on an
+    /// HTTPS listener.
+    pub tls_policy: TlsPolicy,
+    /// Session tickets and the session cache of an HTTPS listener.
+    pub session_resumption: SessionResumption,
+    /// How long a client may take for the TLS handshake and the PROXY protocol
+    /// header before its connection is closed.
+    pub handshake_timeout: Duration,
+    /// No further connections are accepted while this many are in their
+    /// handshake.
+    pub max_concurrent_handshakes: usize,
+}
+
+impl Listener {
+    /// Whether the certificate for `name` may be offered on this listener.
+    pub fn offers(&self, name: &CertificateName) -> bool {
+        self.certificates
+            .as_ref()
+            .is_none_or(|certificates| certificates.contains(name))
+    }
+}
+
+/// Reloads `listener` from the currently loaded config and returns whether its
+/// settings changed. The last known settings are kept if it was removed.
+fn reload(config: &ArcSwap<RuntimeConfig>, listener: &mut Listener) -> bool {
+    match config.load().listener(&listener.name) {
+        Some(current) if current != listener => {
+            *listener = current.clone();
+            true
+        }
+        _ => false,
+    }
+}
 
 pub struct HyperAcceptor<'a, T> {
     acceptor: Pin<Box<dyn Stream<Item = Result<T, io::Error>> + Send + 'a>>,
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_, TcpStream> {
-    type Conn = TcpStream;
+impl hyper::server::accept::Accept for HyperAcceptor<'_, IncomingStream> {
+    type Conn = IncomingStream;
     type Error = io::Error;
 
     fn poll_accept(
@@ -29,8 +98,8 @@ impl hyper::server::accept::Accept for HyperAcceptor<'_, TcpStream> {
     }
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_, TlsStream<TcpStream>> {
-    type Conn = TlsStream<TcpStream>;
+impl hyper::server::accept::Accept for HyperAcceptor<'_, TlsStream<IncomingStream>> {
+    type Conn = TlsStream<IncomingStream>;
     type Error = io::Error;
 
     fn poll_accept(
@@ -49,24 +118,229 @@ pub trait AcceptorProducer<T> {
     ) -> Result<HyperAcceptor<'async_trait, T>, io::Error>;
 }
 
-pub struct Http;
+/// An accepted TCP connection and the address of the client that opened it,
+/// which differs from the peer address if it was sent via PROXY protocol.
+pub struct IncomingStream {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl IncomingStream {
+    async fn accept(
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        proxy_protocol: &Option<TrustedProxies>,
+    ) -> Result<IncomingStream, HandshakeError> {
+        // Performance optimizations: Disable Nagle's algorithm for lower latency
+        let _ = stream.set_nodelay(true);
+        let trusted = proxy_protocol
+            .as_ref()
+            .is_some_and(|sources| sources.contains(&peer_addr.ip()));
+        let remote_addr = if trusted {
+            proxy_protocol::read_header(&mut stream)
+                .await
+                .map_err(HandshakeError::ProxyProtocol)?
+                .unwrap_or(peer_addr)
+        } else {
+            peer_addr
+        };
+        Ok(IncomingStream {
+            stream,
+            remote_addr,
+        })
+    }
+}
+
+impl AsyncRead for IncomingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for IncomingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Why a connection could not be handed to the server.
+#[derive(Debug)]
+enum HandshakeError {
+    Timeout,
+    ProxyProtocol(io::Error),
+    Tls(io::Error),
+}
+
+impl HandshakeError {
+    /// The reason reported in the `handshake_failures_total` metric.
+    fn reason(&self) -> &'static str {
+        match self {
+            HandshakeError::Timeout => "timeout",
+            HandshakeError::ProxyProtocol(_) => "proxy_protocol",
+            HandshakeError::Tls(e) if e.kind() == io::ErrorKind::UnexpectedEof => "closed",
+            HandshakeError::Tls(e) => match e.get_ref().and_then(|it| it.downcast_ref()) {
+                Some(rustls::Error::AlertReceived(_)) => "alert_received",
+                Some(rustls::Error::PeerIncompatible(_)) => "incompatible",
+                Some(
+                    rustls::Error::NoCertificatesPresented | rustls::Error::InvalidCertificate(_),
+                ) => "client_certificate",
+                Some(
+                    rustls::Error::InvalidMessage(_) | rustls::Error::InappropriateMessage { .. },
+                ) => "invalid_message",
+                Some(_) => "tls",
+                None => "io",
+            },
+        }
+    }
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Timeout => write!(f, "Handshake timed out"),
+            HandshakeError::ProxyProtocol(e) => write!(f, "{}", e),
+            HandshakeError::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+        }
+    }
+}
+
+/// Limits the number of connections in their handshake on one listener. The
+/// limit is passed on every [`acquire`](HandshakeLimit::acquire), so that it can
+/// change with config reloads.
+#[derive(Default)]
+struct HandshakeLimit {
+    in_flight: AtomicUsize,
+    released: Notify,
+}
+
+impl HandshakeLimit {
+    async fn acquire(self: &Arc<Self>, max: usize) -> HandshakePermit {
+        loop {
+            let released = self.released.notified();
+            let acquired = self
+                .in_flight
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < max).then_some(n + 1)
+                })
+                .is_ok();
+            if acquired {
+                return HandshakePermit(self.clone());
+            }
+            released.await;
+        }
+    }
+}
+
+struct HandshakePermit(Arc<HandshakeLimit>);
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.released.notify_one();
+    }
+}
+
+/// Accepts connections on `tcp_listener` and runs `handshake` on each of them
+/// in its own task, so that slow clients do not hold up others. Handshakes
+/// taking longer than the `handshake_timeout` of the listener are aborted and
+/// no further connections are accepted while `max_concurrent_handshakes` are in
+/// flight.
+fn accept_concurrently<T, H, F>(
+    tcp_listener: TcpListener,
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    mut listener: Listener,
+    mut handshake: H,
+) -> impl Stream<Item = Result<T, io::Error>>
+where
+    T: Send + 'static,
+    H: FnMut(TcpStream, SocketAddr, &Listener) -> F,
+    F: Future<Output = Result<T, HandshakeError>> + Send + 'static,
+{
+    let limit = Arc::new(HandshakeLimit::default());
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    stream! {
+      loop {
+        reload(&config, &mut listener);
+        let accept = async {
+            let permit = limit.acquire(listener.max_concurrent_handshakes).await;
+            let (socket, peer_addr) = tcp_listener.accept().await?;
+            Ok::<_, io::Error>((socket, peer_addr, permit))
+        };
+        let next = tokio::select! {
+          accepted = accept => Either::Left(accepted),
+          Some(stream) = receiver.recv() => Either::Right(stream),
+        };
+        match next {
+          Either::Right(stream) => yield Ok(stream),
+          Either::Left(accepted) => {
+            let (socket, peer_addr, permit) = accepted?;
+            let name = listener.name.clone();
+            let handshake_timeout = listener.handshake_timeout;
+            let handshake = handshake(socket, peer_addr, &listener);
+            let sender = sender.clone();
+            tokio::spawn(async move {
+              metrics::IN_FLIGHT_HANDSHAKES.with_label_values(&[&name]).inc();
+              let result = timeout(handshake_timeout, handshake)
+                  .await
+                  .unwrap_or(Err(HandshakeError::Timeout));
+              metrics::IN_FLIGHT_HANDSHAKES.with_label_values(&[&name]).dec();
+              drop(permit);
+              match result {
+                Ok(stream) => {
+                  let _ = sender.send(stream);
+                }
+                Err(e) => {
+                  metrics::HANDSHAKE_FAILURES_TOTAL
+                      .with_label_values(&[&name, e.reason()])
+                      .inc();
+                  debug!("Failed to accept connection from {} on '{}': {}", peer_addr, name, e);
+                }
+              }
+            });
+          }
+        }
+      }
+    }
+}
+
+pub struct Http {
+    pub config: Arc<ArcSwap<RuntimeConfig>>,
+    pub listener: Listener,
+}
 
 #[async_trait]
-impl AcceptorProducer<TcpStream> for Http {
+impl AcceptorProducer<IncomingStream> for Http {
     async fn produce_acceptor(
         self,
         address: SocketAddr,
-    ) -> Result<HyperAcceptor<'async_trait, TcpStream>, io::Error> {
-        let listener = TcpListener::bind(address).await?;
-
-        let incoming_stream = stream! {
-          loop {
-            let (socket, _) = listener.accept().await?;
-            // Performance optimizations: Disable Nagle's algorithm for lower latency
-            let _ = socket.set_nodelay(true);
-            yield Ok(socket);
-          }
-        };
+    ) -> Result<HyperAcceptor<'async_trait, IncomingStream>, io::Error> {
+        let tcp_listener = TcpListener::bind(address).await?;
+
+        let incoming_stream = accept_concurrently(
+            tcp_listener,
+            self.config,
+            self.listener,
+            |socket, peer_addr, listener| {
+                let proxy_protocol = listener.proxy_protocol.clone();
+                async move { IncomingStream::accept(socket, peer_addr, &proxy_protocol).await }
+            },
+        );
 
         info!("Started listening for HTTP requests on {}", address);
 
@@ -77,29 +351,50 @@ impl AcceptorProducer<TcpStream> for Http {
 }
 
 pub struct Https {
-    pub tls_config: ServerConfig,
+    pub config: Arc<ArcSwap<RuntimeConfig>>,
+    pub listener: Listener,
 }
 
 #[async_trait]
-impl AcceptorProducer<TlsStream<TcpStream>> for Https {
+impl AcceptorProducer<TlsStream<IncomingStream>> for Https {
     async fn produce_acceptor(
         self,
         address: SocketAddr,
-    ) -> Result<HyperAcceptor<'async_trait, TlsStream<TcpStream>>, io::Error> {
-        let tls_acceptor = TlsAcceptor::from(Arc::new(self.tls_config));
-        let listener = TcpListener::bind(address).await?;
-
-        let incoming_stream = stream! {
-          loop {
-              let (socket, _) = listener.accept().await?;
-              // Performance optimizations: Disable Nagle's algorithm for lower latency
-              let _ = socket.set_nodelay(true);
-              match tls_acceptor.accept(socket).await {
-                Ok(tls_stream) => yield Ok(tls_stream),
-                Err(e) => error!("Failed to accept TLS socket: {}", e)
-              }
-          }
-        };
+    ) -> Result<HyperAcceptor<'async_trait, TlsStream<IncomingStream>>, io::Error> {
+        let tcp_listener = TcpListener::bind(address).await?;
+        let config = self.config;
+        // the TLS settings the acceptor was created with
+        let mut tls_listener = self.listener.clone();
+        let mut tls_acceptor = create_tls_acceptor(&config, &tls_listener);
+
+        let incoming_stream = accept_concurrently(
+            tcp_listener,
+            config.clone(),
+            self.listener,
+            move |socket, peer_addr, listener| {
+                if *listener != tls_listener {
+                    tls_listener = listener.clone();
+                    tls_acceptor = create_tls_acceptor(&config, &tls_listener);
+                }
+                let tls_acceptor = tls_acceptor.clone();
+                let listener = listener.clone();
+                async move {
+                    let incoming =
+                        IncomingStream::accept(socket, peer_addr, &listener.proxy_protocol).await?;
+                    let tls_stream = tls_acceptor
+                        .accept(incoming)
+                        .await
+                        .map_err(HandshakeError::Tls)?;
+                    let protocol = tls_stream
+                        .alpn_protocol()
+                        .unwrap_or_else(|| "none".to_string());
+                    metrics::TLS_CONNECTIONS_TOTAL
+                        .with_label_values(&[&listener.name, &protocol])
+                        .inc();
+                    Ok(tls_stream)
+                }
+            },
+        );
 
         info!("Started listening for HTTPS requests on {}", address);
 
@@ -109,19 +404,145 @@ impl AcceptorProducer<TlsStream<TcpStream>> for Https {
     }
 }
 
+fn create_tls_acceptor(config: &Arc<ArcSwap<RuntimeConfig>>, listener: &Listener) -> TlsAcceptor {
+    info!(
+        "TLS policy of listener '{}': {}",
+        listener.name, listener.tls_policy
+    );
+    TlsAcceptor::from(Arc::new(tls::server_config(config.clone(), listener)))
+}
+
 pub trait RemoteAddress {
     fn remote_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The protocol negotiated via ALPN, only available for TLS connections.
+    fn alpn_protocol(&self) -> Option<String> {
+        None
+    }
+
+    /// The verified certificate the client authenticated with, only available
+    /// for TLS connections on listeners with [`ClientAuth`].
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
 }
 
-impl RemoteAddress for TcpStream {
+impl RemoteAddress for IncomingStream {
     fn remote_addr(&self) -> io::Result<SocketAddr> {
-        self.peer_addr()
+        Ok(self.remote_addr)
     }
 }
 
-impl RemoteAddress for TlsStream<TcpStream> {
+impl RemoteAddress for TlsStream<IncomingStream> {
     fn remote_addr(&self) -> io::Result<SocketAddr> {
         let (stream, _) = self.get_ref();
-        stream.peer_addr()
+        stream.remote_addr()
+    }
+
+    fn alpn_protocol(&self) -> Option<String> {
+        let (_, connection) = self.get_ref();
+        connection
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        let (_, connection) = self.get_ref();
+        let certificate = connection.peer_certificates()?.first()?;
+        ClientCertificate::from_der(&certificate.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{acme::AcmeHandler, server::SharedData};
+    use futures::StreamExt;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn accept_first_byte(
+        handshake_timeout: Duration,
+    ) -> (
+        SocketAddr,
+        Pin<Box<dyn Stream<Item = Result<(TcpStream, u8), io::Error>> + Send>>,
+    ) {
+        let listener = Listener {
+            name: "test".into(),
+            address: "127.0.0.1:0".parse().unwrap(),
+            scheme: Scheme::HTTP,
+            certificates: None,
+            proxy_protocol: None,
+            alpn_protocols: Vec::new(),
+            client_auth: None,
+            tls_policy: TlsPolicy::default(),
+            session_resumption: SessionResumption::default(),
+            handshake_timeout,
+            max_concurrent_handshakes: 2,
+        };
+        let config = Arc::new(ArcSwap::from_pointee(RuntimeConfig {
+            listeners: vec![listener.clone()],
+            shared_data: SharedData {
+                backend_pools: Vec::new(),
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            },
+            certificates: HashMap::new(),
+            certificate_files: HashMap::new(),
+            acme_certificates: HashMap::new(),
+            ocsp_sources: HashMap::new(),
+            default_certificate: None,
+        }));
+        let tcp_listener = TcpListener::bind(listener.address).await.unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let accepted = accept_concurrently(
+            tcp_listener,
+            config,
+            listener,
+            |mut socket, _, _| async move {
+                let byte = socket.read_u8().await.map_err(HandshakeError::Tls)?;
+                Ok((socket, byte))
+            },
+        );
+        (address, Box::pin(accepted))
+    }
+
+    #[tokio::test]
+    async fn slow_handshakes_do_not_block_others() {
+        let (address, mut accepted) = accept_first_byte(Duration::from_secs(10)).await;
+
+        let _slow = TcpStream::connect(address).await.unwrap();
+        let mut fast = TcpStream::connect(address).await.unwrap();
+        fast.write_u8(42).await.unwrap();
+
+        let (_, byte) = accepted.next().await.unwrap().unwrap();
+        assert_eq!(byte, 42);
+    }
+
+    #[tokio::test]
+    async fn handshakes_time_out() {
+        let (address, mut accepted) = accept_first_byte(Duration::from_millis(50)).await;
+
+        let mut slow = TcpStream::connect(address).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(200), accepted.next()).await;
+        assert!(next.is_err());
+        // the server closed the connection
+        assert_eq!(slow.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_handshakes() {
+        let (address, mut accepted) = accept_first_byte(Duration::from_secs(10)).await;
+
+        let _slow = [
+            TcpStream::connect(address).await.unwrap(),
+            TcpStream::connect(address).await.unwrap(),
+        ];
+        let mut waiting = TcpStream::connect(address).await.unwrap();
+        waiting.write_u8(42).await.unwrap();
+
+        let next = tokio::time::timeout(Duration::from_millis(200), accepted.next()).await;
+        assert!(next.is_err());
     }
 }
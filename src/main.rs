@@ -1,13 +1,14 @@
 use arc_swap::{access::Map, ArcSwap};
 use clap::{App, Arg};
-use configuration::{read_initial_config, watch_config, RuntimeConfig};
-use listeners::{AcceptorProducer, Https};
+use configuration::{read_initial_config, watch_certificates, watch_config, RuntimeConfig};
+use futures::Future;
+use listeners::{AcceptorProducer, Https, HyperAcceptor, IncomingStream, Listener};
+use log::{error, info};
 use prometheus::Encoder;
 use server::Scheme;
-use std::{io, sync::Arc};
-use tls::ReconfigurableCertificateResolver;
-use tokio::try_join;
-use tokio_rustls::rustls::ServerConfig;
+use std::{io, sync::Arc, time::Duration};
+use tokio::{sync::oneshot, task::JoinHandle, time::sleep, try_join};
+use tokio_rustls::server::TlsStream;
 use warp::Filter;
 
 mod acme;
@@ -15,14 +16,21 @@ mod algorithms;
 mod backend_pool_matcher;
 mod configuration;
 mod error_response;
+mod forwarded;
 mod health;
 mod http_client;
 mod listeners;
 mod logging;
 mod metrics;
 mod middleware;
+mod ocsp;
+mod proxy_protocol;
+mod retry;
 mod server;
+mod session_tickets;
+mod timeouts;
 mod tls;
+mod upgrade;
 mod utils;
 
 #[tokio::main]
@@ -47,9 +55,16 @@ pub async fn main() -> Result<(), io::Error> {
     let config = read_initial_config(&config_path).await?;
     try_join!(
         watch_config(config_path, config.clone()),
+        watch_certificates(config.clone()),
+        acme::renew_certificates(config.clone()),
+        ocsp::staple_ocsp_responses(config.clone()),
         watch_health(config.clone()),
-        listen_for_http_request(config.clone()),
-        listen_for_https_request(config.clone()),
+        watch_listeners(config.clone(), async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("Failed to listen for shutdown signal");
+            info!("Received shutdown signal, gracefully shutting down...");
+        }),
         serve_metrics()
     )?;
     Ok(())
@@ -59,33 +74,184 @@ async fn watch_health(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Err
     let backend_pools = Map::new(config.clone(), |it: &RuntimeConfig| {
         &it.shared_data.backend_pools
     });
-    let health_interval = Map::new(config.clone(), |it: &RuntimeConfig| &it.health_interval);
-    health::watch_health(backend_pools, health_interval).await;
+    health::watch_health(backend_pools).await;
     Ok(())
 }
 
-async fn listen_for_http_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-    let http = listeners::Http;
-    let address = config.load().http_address;
-    let acceptor = http.produce_acceptor(address).await?;
+// How often the bound listeners are compared against the loaded config
+const LISTENER_RECONCILE_INTERVAL: Duration = Duration::from_secs(1);
 
-    server::create(acceptor, config, Scheme::HTTP).await
+// How often and how long to retry binding an address whose previous listener was just closed
+const REBIND_ATTEMPTS: usize = 40;
+const REBIND_DELAY: Duration = Duration::from_millis(50);
+
+// A listener whose socket is bound, it is closed once `close` is used or dropped
+struct RunningListener {
+    listener: Listener,
+    close: oneshot::Sender<()>,
+    // finishes once the open connections are drained or the server failed
+    server: JoinHandle<()>,
 }
 
-async fn listen_for_https_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-    let certificates = Map::new(config.clone(), |it: &RuntimeConfig| &it.certificates);
-    let cert_resolver = ReconfigurableCertificateResolver::new(certificates);
+// Binds the listeners of the loaded config and keeps them in sync across config reloads.
+// New listeners are bound and removed ones stop accepting and drain their open connections.
+// A listener whose address changed keeps serving on the old one until the new one is bound,
+// unless the new one needs the socket of a listener which is no longer wanted, e.g. because
+// only its scheme changed. Listeners whose server stopped due to an error are bound again.
+// Once `shutdown` completes all listeners are closed for good and their connections drained.
+async fn watch_listeners<S>(
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    shutdown: S,
+) -> Result<(), io::Error>
+where
+    S: Future<Output = ()>,
+{
+    tokio::pin!(shutdown);
+    let mut running: Vec<RunningListener> = Vec::new();
+    // listeners which could not be bound, to log the error only once
+    let mut failed: Vec<Listener> = Vec::new();
+    let mut initial = true;
+    loop {
+        let wanted = config.load().listeners.clone();
+        // servers only finish on their own due to an error, closed ones were removed already
+        running.retain(|it| !it.server.is_finished());
+        for listener in &wanted {
+            if running.iter().any(|it| binds_like(&it.listener, listener)) {
+                continue;
+            }
+            let (blocking, others): (Vec<_>, Vec<_>) = running.into_iter().partition(|it| {
+                it.listener.address == listener.address
+                    && !wanted.iter().any(|other| binds_like(&it.listener, other))
+            });
+            running = others;
+            let released = !blocking.is_empty();
+            for it in blocking {
+                let _ = it.close.send(());
+            }
+            let mut started = start_listener(config.clone(), listener.clone()).await;
+            // the closed server drops its socket the next time it is polled
+            for _ in 0..REBIND_ATTEMPTS {
+                match &started {
+                    Err(e) if released && e.kind() == io::ErrorKind::AddrInUse => {
+                        sleep(REBIND_DELAY).await;
+                        started = start_listener(config.clone(), listener.clone()).await;
+                    }
+                    _ => break,
+                }
+            }
+            match started {
+                Ok(started) => {
+                    failed.retain(|it| it != listener);
+                    running.push(started);
+                }
+                // fail fast on startup, like before hot reloads were supported
+                Err(e) if initial => return Err(e),
+                Err(e) => {
+                    if !failed.contains(listener) {
+                        error!(
+                            "Could not bind listener '{}' on {}: {}",
+                            listener.name, listener.address, e
+                        );
+                        failed.push(listener.clone());
+                    }
+                }
+            }
+        }
+
+        let (keep, close): (Vec<_>, Vec<_>) = running.into_iter().partition(|it| {
+            wanted.iter().any(|listener| {
+                binds_like(&it.listener, listener)
+                    || (listener.name == it.listener.name && failed.contains(listener))
+            })
+        });
+        for it in close {
+            let _ = it.close.send(());
+        }
+        running = keep;
+        failed.retain(|it| wanted.contains(it));
+        initial = false;
+        tokio::select! {
+            _ = sleep(LISTENER_RECONCILE_INTERVAL) => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    let servers = running
+        .into_iter()
+        .map(|it| {
+            let _ = it.close.send(());
+            it.server
+        })
+        .collect::<Vec<_>>();
+    for server in servers {
+        let _ = server.await;
+    }
+    Ok(())
+}
 
-    let tls_config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_cert_resolver(Arc::new(cert_resolver));
+// Whether `a` and `b` can be served by the same socket and server, the acceptors apply
+// changes of all other settings to new connections
+fn binds_like(a: &Listener, b: &Listener) -> bool {
+    a.name == b.name && a.address == b.address && a.scheme == b.scheme
+}
 
-    let https = Https { tls_config };
-    let address = config.load().https_address;
-    let acceptor = https.produce_acceptor(address).await?;
+async fn start_listener(
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    listener: Listener,
+) -> Result<RunningListener, io::Error> {
+    let (close, closed) = oneshot::channel::<()>();
+    let closed = async {
+        let _ = closed.await;
+    };
+    let name = listener.name.clone();
+    let server = match listener.scheme {
+        Scheme::HTTP => {
+            let acceptor = listen_for_http_request(config.clone(), &listener).await?;
+            let server = server::create(acceptor, config, listener.clone(), closed);
+            tokio::spawn(log_listener_error(name, server))
+        }
+        Scheme::HTTPS => {
+            let acceptor = listen_for_https_request(config.clone(), &listener).await?;
+            let server = server::create(acceptor, config, listener.clone(), closed);
+            tokio::spawn(log_listener_error(name, server))
+        }
+    };
+    Ok(RunningListener {
+        listener,
+        close,
+        server,
+    })
+}
+
+async fn log_listener_error<F>(name: String, server: F)
+where
+    F: Future<Output = Result<(), io::Error>>,
+{
+    if let Err(e) = server.await {
+        error!("Listener '{}' stopped due to: {}", name, e);
+    }
+}
+
+async fn listen_for_http_request(
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    listener: &Listener,
+) -> Result<HyperAcceptor<'static, IncomingStream>, io::Error> {
+    let http = listeners::Http {
+        config,
+        listener: listener.clone(),
+    };
+    http.produce_acceptor(listener.address).await
+}
 
-    server::create(acceptor, config, Scheme::HTTPS).await
+async fn listen_for_https_request(
+    config: Arc<ArcSwap<RuntimeConfig>>,
+    listener: &Listener,
+) -> Result<HyperAcceptor<'static, TlsStream<IncomingStream>>, io::Error> {
+    let https = Https {
+        config,
+        listener: listener.clone(),
+    };
+    https.produce_acceptor(listener.address).await
 }
 
 async fn serve_metrics() -> Result<(), io::Error> {
@@ -102,3 +268,107 @@ async fn serve_metrics() -> Result<(), io::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{
+        tests::{certified_key, runtime_config},
+        UpstreamTls,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::{rustls::ServerName, TlsConnector};
+
+    async fn speaks_http(address: std::net::SocketAddr) -> bool {
+        let Ok(mut stream) = TcpStream::connect(address).await else {
+            return false;
+        };
+        let mut response = [0; 8];
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.is_ok()
+            && stream.read_exact(&mut response).await.is_ok()
+            && &response == b"HTTP/1.0"
+    }
+
+    async fn speaks_https(address: std::net::SocketAddr) -> bool {
+        let client_config = UpstreamTls {
+            ca_bundle_path: None,
+            server_name: None,
+            client_certificate: None,
+            insecure_skip_verify: true,
+        }
+        .client_config()
+        .unwrap();
+        let Ok(stream) = TcpStream::connect(address).await else {
+            return false;
+        };
+        TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .is_ok()
+    }
+
+    async fn eventually<F, Fut>(check: F) -> bool
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        for _ in 0..50 {
+            if check().await {
+                return true;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn rebinds_the_address_when_the_scheme_changes() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let certified_key = certified_key("localhost");
+        let mut https = runtime_config(&[("localhost", &certified_key)], None, None);
+        https.listeners[0].address = address;
+        let mut http = https.clone();
+        http.listeners[0].scheme = Scheme::HTTP;
+        let config = Arc::new(ArcSwap::from_pointee(http));
+        tokio::spawn(watch_listeners(config.clone(), std::future::pending()));
+        assert!(eventually(|| speaks_http(address)).await);
+
+        config.store(Arc::new(https));
+
+        assert!(eventually(|| speaks_https(address)).await);
+        assert!(!speaks_http(address).await);
+    }
+
+    #[tokio::test]
+    async fn does_not_rebind_listeners_closed_by_shutdown() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let certified_key = certified_key("localhost");
+        let mut http = runtime_config(&[("localhost", &certified_key)], None, None);
+        http.listeners[0].address = address;
+        http.listeners[0].scheme = Scheme::HTTP;
+        let (shutdown, shutdown_received) = oneshot::channel::<()>();
+        let watching = tokio::spawn(watch_listeners(
+            Arc::new(ArcSwap::from_pointee(http)),
+            async {
+                let _ = shutdown_received.await;
+            },
+        ));
+        assert!(eventually(|| speaks_http(address)).await);
+
+        shutdown.send(()).unwrap();
+
+        let stopped = tokio::time::timeout(Duration::from_secs(5), watching).await;
+        assert!(stopped.unwrap().unwrap().is_ok());
+        sleep(LISTENER_RECONCILE_INTERVAL * 2).await;
+        assert!(!speaks_http(address).await);
+    }
+}
@@ -3,8 +3,8 @@
 use lazy_static::lazy_static;
 use prometheus::{
     opts, register_counter, register_histogram, register_histogram_vec, register_int_counter_vec,
-    register_int_gauge, Counter, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge,
-    TextEncoder,
+    register_int_gauge, register_int_gauge_vec, Counter, Encoder, Histogram, HistogramVec,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
 
 lazy_static! {
@@ -58,6 +58,90 @@ lazy_static! {
     pub static ref HTTP_TIMEOUTS_TOTAL: Counter = register_counter!(
         opts!("http_timeouts_total", "Total number of HTTP request timeouts.")
     ).unwrap();
+
+    // Requests retried on another backend.
+    pub static ref RETRIES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "retries_total", "Total number of requests retried on another backend.",
+        &["reason"]  // "connect_failure", "timeout", "502", "503" or "504"
+    ).unwrap();
+
+    // Whether a backend is up (1, healthy or slow) or down (0, unresponsive).
+    pub static ref BACKEND_UP: IntGaugeVec = register_int_gauge_vec!(
+        "backend_up", "Whether the backend passed its last health check.",
+        &["pool", "backend"]
+    ).unwrap();
+
+    // Number of backends per health state.
+    pub static ref BACKEND_HEALTH_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "backend_health_state", "Number of backends in each health state.",
+        &["state"]  // "healthy", "slow" or "unresponsive"
+    ).unwrap();
+
+    // Health check duration.
+    pub static ref HEALTH_CHECK_DURATION: HistogramVec = register_histogram_vec!(
+        "health_check_duration_seconds", "Duration of backend health checks.",
+        &["pool", "backend"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    ).unwrap();
+
+    // Failed health checks.
+    pub static ref HEALTH_CHECK_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "health_check_failures_total", "Total number of failed backend health checks.",
+        &["reason"]  // "timeout", "connect", "request" or "status"
+    ).unwrap();
+
+    // Number of upgraded connections (e.g. WebSocket) bridged to a backend.
+    pub static ref ACTIVE_UPGRADED_CONNECTIONS: IntGauge = register_int_gauge!(
+        opts!("active_upgraded_connections", "Number of active upgraded connections, e.g. WebSockets.")
+    ).unwrap();
+
+    // Accepted TLS connections by the protocol negotiated via ALPN.
+    pub static ref TLS_CONNECTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "tls_connections_total", "Total number of accepted TLS connections by negotiated protocol.",
+        &["listener", "protocol"]  // "h2", "http/1.1" or "none"
+    ).unwrap();
+
+    // TLS handshakes without a certificate for the requested name.
+    pub static ref TLS_UNMATCHED_SNI_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "tls_unmatched_sni_total", "Total number of TLS handshakes whose SNI matched no certificate.",
+        &["listener", "reason"]  // "no_sni" or "unknown_name"
+    ).unwrap();
+
+    // Connections in their TLS handshake or PROXY protocol header.
+    pub static ref IN_FLIGHT_HANDSHAKES: IntGaugeVec = register_int_gauge_vec!(
+        "in_flight_handshakes", "Number of connections in their handshake.",
+        &["listener"]
+    ).unwrap();
+
+    // Connections closed before their handshake completed.
+    pub static ref HANDSHAKE_FAILURES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "handshake_failures_total", "Total number of failed handshakes by reason.",
+        &["listener", "reason"]  // "timeout", "proxy_protocol", "closed", "alert_received", "incompatible", "client_certificate", "invalid_message", "tls" or "io"
+    ).unwrap();
+
+    // The status of the last ACME order per certificate, 1 for the current one.
+    pub static ref ACME_ORDER_STATUS: IntGaugeVec = register_int_gauge_vec!(
+        "acme_order_status", "Status of the last ACME order per certificate.",
+        &["certificate", "status"]  // "pending", "ready", "processing", "valid", "invalid" or "failed"
+    ).unwrap();
+
+    // Finished ACME orders.
+    pub static ref ACME_ORDERS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "acme_orders_total", "Total number of finished ACME orders by result.",
+        &["certificate", "result"]  // "valid" or "failed"
+    ).unwrap();
+
+    // Requests to the ACME CA which were repeated.
+    pub static ref ACME_REQUEST_RETRIES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "acme_request_retries_total", "Total number of repeated requests to ACME CAs.",
+        &["reason"]  // "bad_nonce" or "temporary_failure"
+    ).unwrap();
+
+    // Fetched OCSP responses for stapling.
+    pub static ref OCSP_FETCHES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "ocsp_fetches_total", "Total number of OCSP response fetches by result.",
+        &["certificate", "result"]  // "success" or "failed"
+    ).unwrap();
 }
 
 #[allow(dead_code)]
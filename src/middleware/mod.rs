@@ -1,11 +1,17 @@
 use crate::{
-    error_response::handle_bad_gateway, http_client::StrategyNotifyHttpConnector, server::Scheme,
+    error_response::handle_backend_error,
+    forwarded::forwarded_header,
+    http_client::{StrategyNotifyHttpConnector, PROXY_PROTOCOL_SOURCE},
+    server::Scheme,
     utils::unwrap_result,
 };
 use async_trait::async_trait;
 use gethostname::gethostname;
-use hyper::{header::HeaderValue, Body, Client, Request, Response, Uri};
-use std::net::SocketAddr;
+use hyper::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Body, Client, Request, Response, StatusCode, Uri, Version,
+};
+use std::net::{IpAddr, SocketAddr};
 
 pub mod authentication;
 pub mod compression;
@@ -89,9 +95,26 @@ pub trait Middleware: Send + Sync + std::fmt::Debug {
 
 pub struct Context<'l> {
     pub client_scheme: &'l Scheme,
+    /// The port of the listener which received the request.
+    pub listener_port: u16,
     pub client_address: &'l SocketAddr,
-    pub backend_uri: Uri,
-    pub client: &'l Client<StrategyNotifyHttpConnector, Body>,
+    /// The address of the client, resolved from the forwarding headers if the
+    /// request was sent by a trusted proxy.
+    pub client_ip: IpAddr,
+    /// Sends the request to a backend server once all middlewares ran.
+    pub backend: &'l dyn SendToBackend,
+}
+
+/// The end of a [`MiddlewareChain`], which sends the request to a backend
+/// server, e.g. selecting another one for retries. The middlewares run only
+/// once per client request regardless.
+#[async_trait]
+pub trait SendToBackend: Send + Sync {
+    async fn send_to_backend(
+        &self,
+        request: Request<Body>,
+        context: &Context<'_>,
+    ) -> Response<Body>;
 }
 
 /// A singly linked list of [`Middleware`]s.
@@ -114,10 +137,8 @@ impl MiddlewareChain {
     /// [`forward_request`](Middleware::forward_request) on the first middleware,
     /// passing it the tail of this chain as an argument to be called recursively.
     ///
-    /// Once this chain is empty this function does the final request
-    /// transformation, setting all appropriate forwarding headers (like
-    /// `x-forwarded-for`) and sends it to the backend server, returning the
-    /// response.
+    /// Once this chain is empty the request is passed to the
+    /// [`SendToBackend`] of the `context`.
     pub async fn forward_request(
         &self,
         request: Request<Body>,
@@ -127,42 +148,68 @@ impl MiddlewareChain {
             MiddlewareChain::Entry { middleware, chain } => {
                 middleware.forward_request(request, chain, context).await
             }
-            MiddlewareChain::Empty => {
-                let backend_request = backend_request(request, context);
-                unwrap_result(
-                    context
-                        .client
-                        .request(backend_request)
-                        .await
-                        .map_err(handle_bad_gateway),
-                )
-            }
+            MiddlewareChain::Empty => context.backend.send_to_backend(request, context).await,
         }
     }
 }
 
-fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
-    let builder = Request::builder().uri(&context.backend_uri);
+/// Does the final request transformation, setting all appropriate forwarding
+/// headers (like `x-forwarded-for`), and sends it to `backend_uri` using
+/// `client`, returning the response.
+pub async fn send_request(
+    request: Request<Body>,
+    context: &Context<'_>,
+    backend_uri: &Uri,
+    client: &Client<StrategyNotifyHttpConnector, Body>,
+) -> Response<Body> {
+    let backend_request = backend_request(request, context, backend_uri);
+    let response =
+        PROXY_PROTOCOL_SOURCE.scope(*context.client_address, client.request(backend_request));
+    unwrap_result(
+        response
+            .await
+            .map(client_response)
+            .map_err(handle_backend_error),
+    )
+}
+
+const FORWARDING_HEADERS: [&str; 6] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-real-ip",
+    "x-forwarded-port",
+    "x-forwarded-proto",
+    "x-forwarded-server",
+];
+
+fn backend_request(request: Request<Body>, context: &Context, backend_uri: &Uri) -> Request<Body> {
+    let builder = Request::builder().uri(backend_uri);
+
+    let mut headers = request.headers().clone();
+    remove_hop_by_hop_headers(&mut headers, is_upgrade(request.headers()));
+    append_via(&mut headers, request.version());
+    let forwarded_for = forwarded_for_header(
+        headers.get("x-forwarded-for"),
+        context.client_address.ip().to_string(),
+    );
+    let forwarded = forwarded_header(
+        headers.get("forwarded"),
+        context.client_address.ip(),
+        context.client_scheme,
+        headers.get("host"),
+    );
+    // set below, client supplied values must not be forwarded in addition
+    for name in FORWARDING_HEADERS {
+        headers.remove(name);
+    }
 
-    let mut builder = request
-        .headers()
+    let mut builder = headers
         .iter()
         .fold(builder, |builder, (key, val)| builder.header(key, val))
-        .header(
-            "x-forwarded-for",
-            forwarded_for_header(
-                request.headers().get("x-forwarded-for"),
-                context.client_address.ip().to_string(),
-            ),
-        )
-        .header("x-real-ip", context.client_address.ip().to_string())
-        .header(
-            "x-forwarded-port",
-            match context.client_scheme {
-                Scheme::HTTP => "80",
-                Scheme::HTTPS => "443",
-            },
-        )
+        .header("x-forwarded-for", forwarded_for)
+        .header("x-real-ip", context.client_ip.to_string())
+        .header("forwarded", forwarded)
+        .header("x-forwarded-port", context.listener_port)
         .header("x-forwarded-proto", context.client_scheme.to_string())
         .method(request.method());
 
@@ -175,6 +222,94 @@ fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
     builder.body(request.into_body()).unwrap()
 }
 
+/// Prepares the `response` of a backend server to be sent to the client by
+/// removing its hop-by-hop headers and adding a `Via` header. The upgrade
+/// headers are kept if the backend switches protocols.
+fn client_response(mut response: Response<Body>) -> Response<Body> {
+    let keep_upgrade = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+    let version = response.version();
+    let headers = response.headers_mut();
+    remove_hop_by_hop_headers(headers, keep_upgrade);
+    append_via(headers, version);
+    response
+}
+
+/// Headers which are only meaningful for a single connection and thus must not
+/// be forwarded by proxies, see
+/// [RFC 9110, section 7.6.1](https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1).
+/// `Proxy-Connection` is not standardized, but still sent by some clients.
+const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Returns whether `Connection` contains the `upgrade` option and `Upgrade` is
+/// present.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    connection_options(headers).any(|option| option.eq_ignore_ascii_case("upgrade"))
+        && headers.contains_key(header::UPGRADE)
+}
+
+fn connection_options(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+}
+
+/// Removes the [`HOP_BY_HOP_HEADERS`] and all headers named in `Connection`.
+/// `TE: trailers` is kept, because it signals end-to-end support for trailers
+/// (required by gRPC). If `keep_upgrade` is set, `Upgrade` and
+/// `Connection: upgrade` are kept so the protocol switch reaches the other side.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let connection_headers = connection_options(headers)
+        .filter_map(|option| HeaderName::from_bytes(option.as_bytes()).ok())
+        .collect::<Vec<_>>();
+    let upgrade = headers.get(header::UPGRADE).cloned();
+    let te_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("trailers"));
+
+    for name in connection_headers.iter().chain(HOP_BY_HOP_HEADERS.iter()) {
+        headers.remove(name);
+    }
+
+    if te_trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+    if let (true, Some(upgrade)) = (keep_upgrade, upgrade) {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, upgrade);
+    }
+}
+
+/// Appends this proxy to the `Via` header, see
+/// [RFC 9110, section 7.6.3](https://www.rfc-editor.org/rfc/rfc9110#section-7.6.3).
+fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let via = HeaderValue::from_str(&format!("{} rust-strom", protocol)).unwrap();
+    headers.append(header::VIA, via);
+}
+
 // According to https://docs.oracle.com/en-us/iaas/Content/Balance/Reference/httpheaders.htm
 fn forwarded_for_header(existing_forwarded_for: Option<&HeaderValue>, client_ip: String) -> String {
     match existing_forwarded_for {
@@ -186,3 +321,153 @@ fn forwarded_for_header(existing_forwarded_for: Option<&HeaderValue>, client_ip:
         None => client_ip,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoBackend;
+
+    #[async_trait]
+    impl SendToBackend for NoBackend {
+        async fn send_to_backend(&self, _: Request<Body>, _: &Context<'_>) -> Response<Body> {
+            unreachable!("only the backend request is built")
+        }
+    }
+
+    fn forward(request: Request<Body>) -> Request<Body> {
+        let context = Context {
+            client_scheme: &Scheme::HTTP,
+            listener_port: 8000,
+            client_address: &"10.0.0.1:4000".parse().unwrap(),
+            client_ip: "10.0.0.1".parse().unwrap(),
+            backend: &NoBackend,
+        };
+        backend_request(
+            request,
+            &context,
+            &"http://127.0.0.1:8080/".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn strips_hop_by_hop_request_headers() {
+        let request = Request::builder()
+            .header("connection", "keep-alive, x-secret")
+            .header("keep-alive", "timeout=5")
+            .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+            .header("transfer-encoding", "chunked")
+            .header("te", "gzip")
+            .header("x-secret", "internal")
+            .header("x-kept", "value")
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = forward(request).headers().clone();
+
+        for name in [
+            "connection",
+            "keep-alive",
+            "proxy-authorization",
+            "transfer-encoding",
+            "te",
+            "x-secret",
+        ] {
+            assert!(!headers.contains_key(name), "{} was forwarded", name);
+        }
+        assert_eq!(headers["x-kept"], "value");
+        assert_eq!(headers["via"], "1.1 rust-strom");
+    }
+
+    #[test]
+    fn connection_header_can_not_remove_forwarding_headers() {
+        let request = Request::builder()
+            .header("connection", "x-forwarded-for, x-real-ip, via")
+            .header("x-forwarded-for", "192.168.0.1")
+            .header("via", "1.0 upstream")
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = forward(request).headers().clone();
+
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        assert_eq!(headers["x-real-ip"], "10.0.0.1");
+        assert_eq!(
+            headers.get_all("via").iter().collect::<Vec<_>>(),
+            vec!["1.1 rust-strom"]
+        );
+    }
+
+    #[test]
+    fn smuggled_transfer_encoding_is_not_forwarded() {
+        let request = Request::builder()
+            .header("content-length", "5")
+            .header("transfer-encoding", "chunked")
+            .header("transfer-encoding", "identity")
+            .body(Body::from("hello"))
+            .unwrap();
+
+        let headers = forward(request).headers().clone();
+
+        assert!(!headers.contains_key("transfer-encoding"));
+        assert_eq!(headers["content-length"], "5");
+    }
+
+    #[test]
+    fn keeps_upgrade_and_te_trailers() {
+        let request = Request::builder()
+            .header("connection", "Upgrade")
+            .header("upgrade", "websocket")
+            .header("te", "trailers")
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = forward(request).headers().clone();
+
+        assert_eq!(headers["connection"], "upgrade");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert_eq!(headers["te"], "trailers");
+    }
+
+    #[test]
+    fn strips_hop_by_hop_response_headers() {
+        let response = Response::builder()
+            .header("connection", "close, x-backend-debug")
+            .header("x-backend-debug", "1")
+            .header("keep-alive", "timeout=5")
+            .header("proxy-authenticate", "Basic")
+            .header("upgrade", "h2c")
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = client_response(response).headers().clone();
+
+        for name in [
+            "connection",
+            "x-backend-debug",
+            "keep-alive",
+            "proxy-authenticate",
+            "upgrade",
+        ] {
+            assert!(!headers.contains_key(name), "{} was forwarded", name);
+        }
+        assert_eq!(headers["via"], "1.1 rust-strom");
+    }
+
+    #[test]
+    fn keeps_upgrade_headers_when_switching_protocols() {
+        let response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = client_response(response).headers().clone();
+
+        assert_eq!(headers["connection"], "upgrade");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert!(headers.contains_key("sec-websocket-accept"));
+    }
+}
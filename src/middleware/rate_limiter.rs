@@ -3,12 +3,12 @@ use crate::error_response::too_many_requests;
 use async_trait::async_trait;
 use hyper::{Body, Request, Response};
 use linked_hash_map::LinkedHashMap;
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use tokio::{sync::Mutex, time::Instant};
 
 #[derive(Debug)]
 pub struct RateLimiter {
-    connections: Mutex<LinkedHashMap<SocketAddr, (u64, Instant)>>,
+    connections: Mutex<LinkedHashMap<IpAddr, (u64, Instant)>>,
     limit: u64,
     window_sec: u64,
 }
@@ -22,22 +22,22 @@ impl RateLimiter {
         }
     }
 
-    async fn register_request(&self, client_address: &SocketAddr) -> bool {
+    async fn register_request(&self, client_ip: IpAddr) -> bool {
         let mut connections = self.connections.lock().await;
         let now = Instant::now();
 
         let old_entries = connections
             .iter()
-            .take_while(|(_client_address, (_count, time))| {
+            .take_while(|(_client_ip, (_count, time))| {
                 now.duration_since(*time).as_secs() > self.window_sec
             })
-            .map(|(client_address, _)| *client_address)
+            .map(|(client_ip, _)| *client_ip)
             .collect::<Vec<_>>();
-        for client_address in old_entries {
-            connections.remove(&client_address);
+        for client_ip in old_entries {
+            connections.remove(&client_ip);
         }
 
-        let count = connections.entry(*client_address).or_insert((0, now));
+        let count = connections.entry(client_ip).or_insert((0, now));
         count.0 += 1;
         count.1 = now;
 
@@ -52,7 +52,7 @@ impl Middleware for RateLimiter {
         request: Request<Body>,
         context: &Context<'_>,
     ) -> Result<Request<Body>, Response<Body>> {
-        if self.register_request(context.client_address).await {
+        if self.register_request(context.client_ip).await {
             Ok(request)
         } else {
             Err(too_many_requests())
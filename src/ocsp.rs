@@ -0,0 +1,481 @@
+use crate::{configuration::RuntimeConfig, metrics::OCSP_FETCHES_TOTAL, tls::UpstreamTls};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use foreign_types::ForeignTypeRef;
+use hyper::{body::to_bytes, header::CONTENT_TYPE, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+use log::{info, warn};
+use openssl::{
+    asn1::Asn1GeneralizedTimeRef,
+    error::ErrorStack,
+    hash::MessageDigest,
+    ocsp::{
+        OcspBasicResponseRef, OcspCertId, OcspCertIdRef, OcspCertStatus, OcspFlag, OcspRequest,
+        OcspResponse, OcspResponseStatus,
+    },
+    stack::Stack,
+    x509::{store::X509StoreBuilder, verify::X509VerifyFlags, X509},
+};
+use openssl_sys as ffi;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind::InvalidData},
+    os::raw::c_long,
+    path::PathBuf,
+    ptr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+/// Where the OCSP responses stapled to a certificate come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OcspSource {
+    /// The responder named in the authority information access extension of
+    /// the certificate.
+    Responder,
+    /// A DER encoded response, e.g. fetched with `openssl ocsp -respout` for
+    /// air-gapped setups.
+    File(PathBuf),
+}
+
+/// A verified OCSP response for a certificate.
+#[derive(Debug, Clone)]
+struct Staple {
+    response: Vec<u8>,
+    next_update: DateTime<Utc>,
+}
+
+/// The staple of a certificate and when it is fetched again.
+#[derive(Debug, Default)]
+struct CachedStaple {
+    staple: Option<Staple>,
+    refresh_at: Option<DateTime<Utc>>,
+}
+
+/// How often the certificates are checked for missing or outdated staples.
+const OCSP_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// The delay after a failed fetch.
+const OCSP_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Responses are fetched again at least this often, responses without
+/// `nextUpdate` expire this long after their `thisUpdate`.
+const OCSP_MAX_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const OCSP_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tolerated clock skew between RustStrom and the responder.
+const OCSP_MAX_CLOCK_SKEW_SECONDS: u32 = 5 * 60;
+
+/// Staples OCSP responses to the certificates with an [`OcspSource`]. A
+/// response is fetched again halfway to its `nextUpdate`, or after
+/// [`OCSP_RETRY_INTERVAL`] if that failed. The previous response is stapled
+/// until it expires, so clients are not affected by short responder outages.
+///
+/// Responses are cached per certificate, so reloaded or renewed certificates
+/// get a staple within [`OCSP_CHECK_INTERVAL`].
+pub async fn staple_ocsp_responses(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+    // keyed by the DER of the end entity certificate
+    let mut cache: HashMap<Vec<u8>, CachedStaple> = HashMap::new();
+    loop {
+        let current = config.load_full();
+        cache.retain(|leaf, _| {
+            current
+                .certificates
+                .values()
+                .any(|it| it.cert.first().is_some_and(|cert| &cert.0 == leaf))
+        });
+        for (certificate_name, source) in &current.ocsp_sources {
+            let Some(certified_key) = current.certificates.get(certificate_name) else {
+                continue;
+            };
+            let Some(leaf) = certified_key.cert.first().map(|it| it.0.clone()) else {
+                continue;
+            };
+            let name = certificate_name.to_string();
+            let cached = cache.entry(leaf.clone()).or_default();
+            if cached.refresh_at.is_none_or(|it| it <= Utc::now()) {
+                match fetch_staple(certified_key, source).await {
+                    Ok(staple) => {
+                        OCSP_FETCHES_TOTAL
+                            .with_label_values(&[&name, "success"])
+                            .inc();
+                        info!(
+                            "Fetched OCSP response for '{}', valid until {}",
+                            name, staple.next_update
+                        );
+                        cached.refresh_at = Some(refresh_time(Utc::now(), staple.next_update));
+                        cached.staple = Some(staple);
+                    }
+                    Err(e) => {
+                        OCSP_FETCHES_TOTAL
+                            .with_label_values(&[&name, "failed"])
+                            .inc();
+                        warn!(
+                            "Could not fetch OCSP response for '{}', retrying in {:?}: {}",
+                            name, OCSP_RETRY_INTERVAL, e
+                        );
+                        cached.refresh_at = Some(Utc::now() + OCSP_RETRY_INTERVAL);
+                    }
+                }
+            }
+            if cached
+                .staple
+                .as_ref()
+                .is_some_and(|it| it.next_update <= Utc::now())
+            {
+                warn!("Stopped stapling the expired OCSP response for '{}'", name);
+                cached.staple = None;
+            }
+
+            let response = cached.staple.as_ref().map(|it| it.response.clone());
+            if certified_key.ocsp == response {
+                continue;
+            }
+            config.rcu(|config| {
+                let mut config = RuntimeConfig::clone(config);
+                // the certificate may have been replaced while fetching
+                if let Some(certified_key) = config
+                    .certificates
+                    .get_mut(certificate_name)
+                    .filter(|it| it.cert.first().is_some_and(|cert| cert.0 == leaf))
+                {
+                    *certified_key = CertifiedKey {
+                        ocsp: response.clone(),
+                        ..certified_key.clone()
+                    };
+                }
+                config
+            });
+        }
+        sleep(OCSP_CHECK_INTERVAL).await;
+    }
+}
+
+/// Halfway to `next_update`, but within [`OCSP_MAX_REFRESH_INTERVAL`] and not
+/// before [`OCSP_CHECK_INTERVAL`] passed.
+fn refresh_time(now: DateTime<Utc>, next_update: DateTime<Utc>) -> DateTime<Utc> {
+    let half = (next_update - now) / 2;
+    let half = half
+        .to_std()
+        .unwrap_or_default()
+        .clamp(OCSP_CHECK_INTERVAL, OCSP_MAX_REFRESH_INTERVAL);
+    now + half
+}
+
+async fn fetch_staple(certified_key: &CertifiedKey, source: &OcspSource) -> io::Result<Staple> {
+    let (leaf, issuer) = leaf_and_issuer(certified_key)?;
+    let response = match source {
+        OcspSource::Responder => {
+            let url = responder_url(&leaf)?;
+            let mut request = OcspRequest::new()?;
+            request.add_id(OcspCertId::from_cert(
+                MessageDigest::sha1(),
+                &leaf,
+                &issuer,
+            )?)?;
+            timeout(OCSP_REQUEST_TIMEOUT, post_request(&url, request.to_der()?))
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "OCSP responder timed out")
+                })??
+        }
+        OcspSource::File(path) => fs::read(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not read '{}': {}", path.display(), e),
+            )
+        })?,
+    };
+    let next_update = verify_response(&response, &leaf, &issuer)?;
+    Ok(Staple {
+        response,
+        next_update,
+    })
+}
+
+/// The end entity certificate and its issuer, which must be the second
+/// certificate of the chain.
+fn leaf_and_issuer(certified_key: &CertifiedKey) -> io::Result<(X509, X509)> {
+    match certified_key.cert.as_slice() {
+        [leaf, issuer, ..] => Ok((X509::from_der(&leaf.0)?, X509::from_der(&issuer.0)?)),
+        _ => Err(io::Error::new(
+            InvalidData,
+            "The certificate file does not contain the issuer certificate",
+        )),
+    }
+}
+
+fn responder_url(leaf: &X509) -> io::Result<String> {
+    leaf.ocsp_responders()?
+        .iter()
+        .map(|it| it.to_string())
+        .find(|it| it.starts_with("http://") || it.starts_with("https://"))
+        .ok_or_else(|| io::Error::new(InvalidData, "The certificate names no OCSP responder"))
+}
+
+async fn post_request(url: &str, request: Vec<u8>) -> io::Result<Vec<u8>> {
+    let tls_config = UpstreamTls {
+        ca_bundle_path: None,
+        server_name: None,
+        client_certificate: None,
+        insecure_skip_verify: false,
+    }
+    .client_config()?;
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build::<_, Body>(connector);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Body::from(request))
+        .map_err(|e| io::Error::new(InvalidData, e))?;
+    let response = client.request(request).await.map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!(
+            "OCSP responder '{}' answered with {}",
+            url,
+            response.status()
+        )));
+    }
+    let body = to_bytes(response.into_body())
+        .await
+        .map_err(io::Error::other)?;
+    Ok(body.to_vec())
+}
+
+/// Verifies that `response` is signed by the issuer (or a responder it
+/// delegated to) and reports `leaf` as good. Returns its `nextUpdate`.
+fn verify_response(response: &[u8], leaf: &X509, issuer: &X509) -> io::Result<DateTime<Utc>> {
+    let invalid = |message: &str| io::Error::new(InvalidData, message.to_string());
+    let response = OcspResponse::from_der(response)
+        .map_err(|e| io::Error::new(InvalidData, format!("Invalid OCSP response: {}", e)))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(io::Error::new(
+            InvalidData,
+            format!(
+                "OCSP responder answered with status {}",
+                response.status().as_raw()
+            ),
+        ));
+    }
+    let basic = response.basic()?;
+    let mut certificates = Stack::new()?;
+    certificates.push(issuer.clone())?;
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(issuer.clone())?;
+    // the issuer is usually an intermediate, the chain ends there
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    basic
+        .verify(&certificates, &store.build(), OcspFlag::empty())
+        .map_err(|e| io::Error::new(InvalidData, format!("Invalid OCSP signature: {}", e)))?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?;
+    let status = find_status(&basic, &id)
+        .ok_or_else(|| invalid("The OCSP response does not cover the certificate"))?;
+    if status.status == OcspCertStatus::REVOKED {
+        return Err(invalid("The certificate is revoked"));
+    } else if status.status != OcspCertStatus::GOOD {
+        return Err(invalid("The OCSP responder does not know the certificate"));
+    }
+    if !status.valid {
+        return Err(invalid("The OCSP response is outdated"));
+    }
+    let this_update = parse_time(&status.this_update)
+        .ok_or_else(|| invalid("Invalid thisUpdate in the OCSP response"))?;
+    match &status.next_update {
+        Some(next_update) => parse_time(next_update)
+            .ok_or_else(|| invalid("Invalid nextUpdate in the OCSP response")),
+        None => Ok(this_update + OCSP_MAX_REFRESH_INTERVAL),
+    }
+}
+
+/// The status of a certificate in an OCSP response.
+struct CertStatus {
+    status: OcspCertStatus,
+    /// Whether the response is current, responses without `nextUpdate` for
+    /// [`OCSP_MAX_REFRESH_INTERVAL`] after their `thisUpdate`
+    valid: bool,
+    this_update: String,
+    next_update: Option<String>,
+}
+
+/// Finds the status of `id` in `basic`. `OcspBasicResponseRef::find_status`
+/// can not be used, it assumes that the optional `nextUpdate` is present.
+fn find_status(basic: &OcspBasicResponseRef, id: &OcspCertIdRef) -> Option<CertStatus> {
+    let mut status = ffi::V_OCSP_CERTSTATUS_UNKNOWN;
+    let mut reason = ffi::OCSP_REVOKED_STATUS_NOSTATUS;
+    let mut revocation_time = ptr::null_mut();
+    let mut this_update = ptr::null_mut();
+    let mut next_update = ptr::null_mut();
+    // SAFETY: the pointers are valid and the times returned point into
+    // `basic`, which outlives them
+    unsafe {
+        let found = ffi::OCSP_resp_find_status(
+            basic.as_ptr(),
+            id.as_ptr(),
+            &mut status,
+            &mut reason,
+            &mut revocation_time,
+            &mut this_update,
+            &mut next_update,
+        );
+        if found != 1 || this_update.is_null() {
+            return None;
+        }
+        let max_age = match next_update.is_null() {
+            true => OCSP_MAX_REFRESH_INTERVAL.as_secs() as c_long,
+            false => -1,
+        };
+        let valid = ffi::OCSP_check_validity(
+            this_update,
+            next_update,
+            OCSP_MAX_CLOCK_SKEW_SECONDS as c_long,
+            max_age,
+        ) == 1;
+        if !valid {
+            // drops the error OpenSSL queued
+            let _ = ErrorStack::get();
+        }
+        Some(CertStatus {
+            status: OcspCertStatus::from_raw(status),
+            valid,
+            this_update: Asn1GeneralizedTimeRef::from_ptr(this_update).to_string(),
+            next_update: (!next_update.is_null())
+                .then(|| Asn1GeneralizedTimeRef::from_ptr(next_update).to_string()),
+        })
+    }
+}
+
+/// Parses times as printed by OpenSSL, e.g. `Oct  9 12:00:00 2026 GMT`.
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time, "%b %e %H:%M:%S%.f %Y GMT")
+        .ok()
+        .map(|it| it.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parses_openssl_times() {
+        let expected = Utc.with_ymd_and_hms(2026, 10, 9, 12, 0, 5).unwrap();
+        assert_eq!(parse_time("Oct  9 12:00:05 2026 GMT"), Some(expected));
+        assert_eq!(
+            parse_time("Oct 19 12:00:05 2026 GMT"),
+            Some(expected + chrono::Duration::days(10))
+        );
+        assert_eq!(parse_time("2026-10-09"), None);
+    }
+
+    #[test]
+    fn refreshes_halfway_to_next_update() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 9, 12, 0, 0).unwrap();
+        let hours = chrono::Duration::hours;
+        assert_eq!(refresh_time(now, now + hours(8)), now + hours(4));
+        assert_eq!(refresh_time(now, now + hours(24 * 7)), now + hours(24));
+        assert_eq!(refresh_time(now, now), now + OCSP_CHECK_INTERVAL);
+    }
+
+    extern "C" {
+        fn OCSP_basic_add1_status(
+            response: *mut ffi::OCSP_BASICRESP,
+            id: *mut ffi::OCSP_CERTID,
+            status: std::os::raw::c_int,
+            reason: std::os::raw::c_int,
+            revocation_time: *mut ffi::ASN1_TIME,
+            this_update: *mut ffi::ASN1_TIME,
+            next_update: *mut ffi::ASN1_TIME,
+        ) -> *mut std::ffi::c_void;
+        fn OCSP_basic_sign(
+            response: *mut ffi::OCSP_BASICRESP,
+            signer: *mut ffi::X509,
+            key: *mut ffi::EVP_PKEY,
+            digest: *const ffi::EVP_MD,
+            certificates: *mut std::ffi::c_void,
+            flags: std::os::raw::c_ulong,
+        ) -> std::os::raw::c_int;
+    }
+
+    /// A DER encoded response of `issuer` reporting `leaf` as good since
+    /// `this_update`, without `nextUpdate`.
+    fn response_without_next_update(
+        leaf: &X509,
+        issuer: &X509,
+        issuer_key: &openssl::pkey::PKey<openssl::pkey::Private>,
+        this_update: i64,
+    ) -> Vec<u8> {
+        use foreign_types::ForeignType;
+        use openssl::{asn1::Asn1Time, ocsp::OcspBasicResponse};
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).unwrap();
+        let this_update = Asn1Time::from_unix(this_update).unwrap();
+        unsafe {
+            let basic = OcspBasicResponse::from_ptr(ffi::OCSP_BASICRESP_new());
+            assert!(!OCSP_basic_add1_status(
+                basic.as_ptr(),
+                id.as_ptr(),
+                ffi::V_OCSP_CERTSTATUS_GOOD,
+                0,
+                ptr::null_mut(),
+                this_update.as_ptr(),
+                ptr::null_mut(),
+            )
+            .is_null());
+            assert_eq!(
+                OCSP_basic_sign(
+                    basic.as_ptr(),
+                    issuer.as_ptr(),
+                    issuer_key.as_ptr(),
+                    ffi::EVP_sha256(),
+                    ptr::null_mut(),
+                    0,
+                ),
+                1
+            );
+            OcspResponse::from_ptr(ffi::OCSP_response_create(
+                OcspResponseStatus::SUCCESSFUL.as_raw(),
+                basic.as_ptr(),
+            ))
+            .to_der()
+            .unwrap()
+        }
+    }
+
+    #[test]
+    fn accepts_responses_without_next_update_for_a_bounded_time() {
+        let (issuer, issuer_key) = crate::tls::tests::issue("ca.example.com", None);
+        let (leaf, _) = crate::tls::tests::issue("example.com", Some((&issuer, &issuer_key)));
+        // as loaded from a file, with the serial number encoded as in the response
+        let leaf = X509::from_der(&leaf.to_der().unwrap()).unwrap();
+        let hour_ago = Utc::now().timestamp() - 60 * 60;
+
+        let response = response_without_next_update(&leaf, &issuer, &issuer_key, hour_ago);
+        let next_update = verify_response(&response, &leaf, &issuer).unwrap();
+        let expected = Utc.timestamp_opt(hour_ago, 0).unwrap() + OCSP_MAX_REFRESH_INTERVAL;
+        assert_eq!(next_update, expected);
+
+        let two_days_ago = hour_ago - 47 * 60 * 60;
+        let response = response_without_next_update(&leaf, &issuer, &issuer_key, two_days_ago);
+        let error = verify_response(&response, &leaf, &issuer).unwrap_err();
+        assert!(error.to_string().contains("outdated"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unsuccessful_responses() {
+        let (issuer, issuer_key) = crate::tls::tests::issue("ca.example.com", None);
+        let (leaf, _) = crate::tls::tests::issue("example.com", Some((&issuer, &issuer_key)));
+        let response = OcspResponse::create(OcspResponseStatus::TRY_LATER, None)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let error = verify_response(&response, &leaf, &issuer).unwrap_err();
+        assert!(error.to_string().contains("status 3"), "{}", error);
+        assert!(verify_response(b"garbage", &leaf, &issuer).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::{self, ErrorKind::InvalidData},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a v1 or v2
+/// [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+/// header from `stream`, returning the address of the client. The address is `None` if the sender did not
+/// forward a client connection, e.g. for its own health checks.
+///
+/// Reads no further than the end of the header, so that `stream` can be used
+/// for the proxied connection afterwards.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // both versions are at least 12 bytes long
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid_header("v1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(invalid_header("missing signature"))
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid_header("unsupported command")),
+    }
+    // TLVs following the addresses are ignored
+    let source = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        1 | 2 => return Err(invalid_header("address block is too short")),
+        // UNSPEC or UNIX
+        _ => None,
+    };
+    Ok(source)
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid_header("v1 header is not ASCII"))?;
+    let fields = line.trim_end_matches("\r\n").split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid_header("invalid source address"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid_header("invalid source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_header("malformed v1 header")),
+    }
+}
+
+/// Encodes a v2 header announcing a connection from `source` to `destination`.
+/// Uses IPv4-mapped IPv6 addresses if the families of both addresses differ.
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // version 2, PROXY command
+    header.push(0x21);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            // TCP over IPv4
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+        }
+        (source_ip, destination_ip) => {
+            // TCP over IPv6
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(source_ip).octets());
+            header.extend_from_slice(&to_ipv6(destination_ip).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+/// Encodes a v2 header for a connection which does not forward a client, e.g.
+/// a health check.
+pub fn encode_v2_local() -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // version 2, LOCAL command, UNSPEC family without addresses
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid_header(reason: &str) -> io::Error {
+    io::Error::new(
+        InvalidData,
+        format!("Invalid PROXY protocol header: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+        read_header(&mut bytes).await
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+                .await
+                .unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1\r\n").await.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trips_v2_headers() {
+        let source = "192.0.2.1:56324".parse().unwrap();
+        let destination = "198.51.100.1:443".parse().unwrap();
+        let header = encode_v2(source, destination);
+        assert_eq!(read(&header).await.unwrap(), Some(source));
+
+        let source = "[2001:db8::1]:56324".parse().unwrap();
+        let header = encode_v2(source, destination);
+        assert_eq!(read(&header).await.unwrap(), Some(source));
+
+        assert_eq!(read(&encode_v2_local()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_after_the_header() {
+        let bytes = [
+            encode_v2(
+                "192.0.2.1:1".parse().unwrap(),
+                "192.0.2.2:2".parse().unwrap(),
+            ),
+            b"GET /".to_vec(),
+        ]
+        .concat();
+        let mut stream = bytes.as_slice();
+
+        read_header(&mut stream).await.unwrap();
+
+        assert_eq!(stream, b"GET /");
+    }
+}
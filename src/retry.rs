@@ -0,0 +1,258 @@
+use crate::error_response::BackendFailure;
+use hyper::{Body, Method, Response, StatusCode};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+/// A condition under which a failed request is sent to another backend.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub enum RetryCondition {
+    /// No connection to the backend could be established, so the request was
+    /// never sent. Retried regardless of the request method.
+    ConnectFailure,
+    /// The backend did not respond within the request timeout.
+    Timeout,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+}
+
+impl RetryCondition {
+    fn matches(&self, response: &Response<Body>) -> bool {
+        let failure = response.extensions().get::<BackendFailure>();
+        match self {
+            RetryCondition::ConnectFailure => failure == Some(&BackendFailure::Connect),
+            RetryCondition::Timeout => failure == Some(&BackendFailure::Timeout),
+            RetryCondition::BadGateway => response.status() == StatusCode::BAD_GATEWAY,
+            RetryCondition::ServiceUnavailable => {
+                response.status() == StatusCode::SERVICE_UNAVAILABLE
+            }
+            RetryCondition::GatewayTimeout => response.status() == StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// The label of this condition in the `retries_total` metric.
+    pub fn label(&self) -> &'static str {
+        match self {
+            RetryCondition::ConnectFailure => "connect_failure",
+            RetryCondition::Timeout => "timeout",
+            RetryCondition::BadGateway => "502",
+            RetryCondition::ServiceUnavailable => "503",
+            RetryCondition::GatewayTimeout => "504",
+        }
+    }
+}
+
+/// Decides whether a request to a backend of a
+/// [`BackendPool`](crate::server::BackendPool) is retried on another backend.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one, `1` disables retries.
+    pub max_attempts: usize,
+    pub retry_on: Vec<RetryCondition>,
+    /// Only retry requests with idempotent methods, except on
+    /// [`RetryCondition::ConnectFailure`].
+    pub idempotent_only: bool,
+    pub budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            retry_on: vec![RetryCondition::ConnectFailure],
+            idempotent_only: true,
+            budget: RetryBudget::new(0.2, 10.0),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Returns the condition the `response` to attempt number `attempt` meets
+    /// if the request should be retried. Withdraws from the retry budget if so.
+    pub fn should_retry(
+        &self,
+        method: &Method,
+        attempt: usize,
+        response: &Response<Body>,
+    ) -> Option<RetryCondition> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let condition = *self
+            .retry_on
+            .iter()
+            .find(|condition| condition.matches(response))?;
+        if self.idempotent_only
+            && condition != RetryCondition::ConnectFailure
+            && !is_idempotent(method)
+        {
+            return None;
+        }
+        if !self.budget.withdraw() {
+            return None;
+        }
+        Some(condition)
+    }
+}
+
+/// Idempotent methods according to
+/// [RFC 9110, section 9.2.2](https://www.rfc-editor.org/rfc/rfc9110#section-9.2.2).
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// A token bucket limiting retries to a fraction of all requests, so that an
+/// overloaded pool is not hit by a retry storm.
+///
+/// Every request deposits `ratio` tokens, every retry withdraws one. The bucket
+/// holds at most `burst` tokens and starts full.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    burst: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64, burst: f64) -> RetryBudget {
+        RetryBudget {
+            ratio,
+            burst,
+            tokens: Mutex::new(burst),
+        }
+    }
+
+    pub fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(self.burst);
+    }
+
+    /// Continues with the tokens left in `previous`, the budget this one
+    /// replaces after a config reload, so that reloads do not refill it.
+    pub fn take_over(&self, previous: &RetryBudget) {
+        let tokens = *previous.tokens.lock().unwrap();
+        *self.tokens.lock().unwrap() = tokens.min(self.burst);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_response::bad_gateway;
+
+    fn connect_failure() -> Response<Body> {
+        let mut response = bad_gateway();
+        response.extensions_mut().insert(BackendFailure::Connect);
+        response
+    }
+
+    fn policy(retry_on: Vec<RetryCondition>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            retry_on,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn retries_connect_failure_of_non_idempotent_method() {
+        let policy = policy(vec![RetryCondition::ConnectFailure]);
+
+        assert_eq!(
+            policy.should_retry(&Method::POST, 1, &connect_failure()),
+            Some(RetryCondition::ConnectFailure)
+        );
+        assert_eq!(policy.should_retry(&Method::POST, 1, &bad_gateway()), None);
+    }
+
+    #[test]
+    fn retries_status_only_for_idempotent_methods() {
+        let policy = policy(vec![RetryCondition::BadGateway]);
+
+        assert_eq!(
+            policy.should_retry(&Method::GET, 1, &bad_gateway()),
+            Some(RetryCondition::BadGateway)
+        );
+        assert_eq!(policy.should_retry(&Method::POST, 1, &bad_gateway()), None);
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let policy = policy(vec![RetryCondition::ConnectFailure]);
+
+        assert!(policy
+            .should_retry(&Method::GET, 2, &connect_failure())
+            .is_some());
+        assert!(policy
+            .should_retry(&Method::GET, 3, &connect_failure())
+            .is_none());
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let policy = RetryPolicy {
+            budget: RetryBudget::new(0.5, 1.0),
+            ..policy(vec![RetryCondition::ConnectFailure])
+        };
+
+        assert!(policy
+            .should_retry(&Method::GET, 1, &connect_failure())
+            .is_some());
+        assert!(policy
+            .should_retry(&Method::GET, 1, &connect_failure())
+            .is_none());
+        policy.budget.deposit();
+        policy.budget.deposit();
+        assert!(policy
+            .should_retry(&Method::GET, 1, &connect_failure())
+            .is_some());
+    }
+
+    #[test]
+    fn budget_survives_reloads() {
+        let previous = RetryBudget::new(0.5, 2.0);
+        assert!(previous.withdraw());
+        assert!(previous.withdraw());
+
+        let budget = RetryBudget::new(0.5, 2.0);
+        budget.take_over(&previous);
+        assert!(!budget.withdraw());
+
+        previous.deposit();
+        previous.deposit();
+        previous.deposit();
+        previous.deposit();
+        let smaller = RetryBudget::new(0.5, 1.0);
+        smaller.take_over(&previous);
+        assert!(smaller.withdraw());
+        assert!(!smaller.withdraw());
+    }
+
+    #[test]
+    fn ignores_successful_responses() {
+        let policy = policy(vec![
+            RetryCondition::BadGateway,
+            RetryCondition::ServiceUnavailable,
+        ]);
+        let response = Response::new(Body::empty());
+
+        assert_eq!(policy.should_retry(&Method::GET, 1, &response), None);
+    }
+}
@@ -2,23 +2,32 @@ use crate::{
     acme::AcmeHandler,
     algorithms::{self, LoadBalancingStrategy},
     backend_pool_matcher::BackendPoolMatcher,
-    configuration::RuntimeConfig,
-    error_response::{bad_gateway, not_found},
+    configuration::{LoadBalancingStrategyConfig, RuntimeConfig},
+    error_response::{bad_gateway, bad_request, forbidden, not_found},
+    forwarded::TrustedProxies,
     health::{HealthConfig, Healthiness},
-    http_client::StrategyNotifyHttpConnector,
-    listeners::RemoteAddress,
+    http_client::{BackendProtocol, Http2Settings, StrategyNotifyHttpConnector},
+    listeners::{Listener, RemoteAddress},
     metrics,
-    middleware::MiddlewareChain,
+    middleware::{self, MiddlewareChain, SendToBackend},
+    retry::RetryPolicy,
+    timeouts::{timeouts_for, TimeoutOverride, Timeouts},
+    tls::{plain_client_config, ClientCertificate, ClientCertificateHeaders, UpstreamTls},
+    upgrade,
 };
 use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use bytes::Bytes;
 use futures::Future;
 use futures::TryFutureExt;
 use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, HOST},
     server::accept::Accept,
     service::{make_service_fn, Service},
-    Body, Client, Request, Response, Server,
+    Body, Client, HeaderMap, Method, Request, Response, Server, Uri, Version,
 };
-use log::debug;
+use log::{debug, info};
 use serde::Deserialize;
 use std::{
     collections::HashSet,
@@ -27,31 +36,44 @@ use std::{
     io,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
     time::Duration,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::ClientConfig;
 
-pub async fn create<I, IE, IO>(
+/// Serves requests accepted by `acceptor` until `close` completes, e.g. because
+/// the listener was removed from the config or on shutdown. Connections which
+/// are already open are drained before returning.
+pub async fn create<I, IE, IO, C>(
     acceptor: I,
     config: Arc<ArcSwap<RuntimeConfig>>,
-    scheme: Scheme,
+    listener: Listener,
+    close: C,
 ) -> Result<(), io::Error>
 where
+    C: Future<Output = ()>,
     I: Accept<Conn = IO, Error = IE>,
     IE: Into<Box<dyn Error + Send + Sync>>,
     IO: AsyncRead + AsyncWrite + Unpin + Send + RemoteAddress + 'static,
 {
+    let listener = Arc::new(listener);
+    let name = listener.name.clone();
     let service = make_service_fn(move |stream: &IO| {
         let client_address = stream.remote_addr().expect("No remote SocketAddr");
+        let alpn_protocol = stream.alpn_protocol();
+        let client_certificate = stream.client_certificate().map(Arc::new);
         let config = config.clone();
+        let listener = listener.clone();
 
         async move {
             Ok::<_, io::Error>(MainService {
                 client_address,
+                alpn_protocol,
+                client_certificate,
                 config,
-                scheme,
+                listener,
             })
         }
     });
@@ -63,11 +85,9 @@ where
         .serve(service);
 
     // Graceful shutdown handling
-    let graceful = server.with_graceful_shutdown(async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to listen for shutdown signal");
-        log::info!("Received shutdown signal, gracefully shutting down...");
+    let graceful = server.with_graceful_shutdown(async move {
+        close.await;
+        log::info!("Closing listener '{}', draining its connections", name);
     });
 
     graceful
@@ -80,8 +100,12 @@ where
 
 pub struct MainService {
     client_address: SocketAddr,
+    /// The protocol negotiated via ALPN if the connection uses TLS.
+    alpn_protocol: Option<String>,
+    /// The verified certificate of the client if the listener asks for one.
+    client_certificate: Option<Arc<ClientCertificate>>,
     config: Arc<ArcSwap<RuntimeConfig>>,
-    scheme: Scheme,
+    listener: Arc<Listener>,
 }
 
 impl Service<Request<Body>> for MainService {
@@ -96,14 +120,44 @@ impl Service<Request<Body>> for MainService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
-        debug!(
-            "{:#?} {} {}",
-            request.version(),
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        // HTTP/2 requests carry the host in the :authority pseudo header only
+        if !request.headers().contains_key(HOST) {
+            if let Some(host) = request
+                .uri()
+                .authority()
+                .and_then(|it| HeaderValue::from_str(it.as_str()).ok())
+            {
+                request.headers_mut().insert(HOST, host);
+            }
+        }
+        let access_log = format!(
+            "{} \"{} {} {:?}\" listener={} alpn={}",
+            self.client_address.ip(),
             request.method(),
-            request.uri()
+            request.uri(),
+            request.version(),
+            self.listener.name,
+            self.alpn_protocol.as_deref().unwrap_or("none")
         );
+        let start_time = std::time::Instant::now();
+        let response = self.respond(request);
+        Box::pin(async move {
+            let response = response.await?;
+            info!(
+                target: "access",
+                "{} {} {:.3}s",
+                access_log,
+                response.status().as_u16(),
+                start_time.elapsed().as_secs_f64()
+            );
+            Ok(response)
+        })
+    }
+}
 
+impl MainService {
+    fn respond(&mut self, mut request: Request<Body>) -> <Self as Service<Request<Body>>>::Future {
         // Increment total requests counter
         metrics::HTTP_REQUESTS_TOTAL.inc();
 
@@ -118,10 +172,28 @@ impl Service<Request<Body>> for MainService {
             return Box::pin(async move { Ok(response) });
         }
 
-        match pool_by_req(shared_data, &request, &self.scheme) {
+        let peer_ip = self.client_address.ip();
+        let trusted_proxies = &shared_data.trusted_proxies;
+        trusted_proxies.sanitize(peer_ip, request.headers_mut());
+        let client_ip = trusted_proxies.client_ip(peer_ip, request.headers());
+        shared_data
+            .client_certificate_headers
+            .apply(self.client_certificate.as_deref(), request.headers_mut());
+        if let Some(client_certificate) = &self.client_certificate {
+            request.extensions_mut().insert(client_certificate.clone());
+        }
+
+        match pool_by_req(shared_data, &request, &self.listener) {
+            Some(pool) if pool.client_certificate_required && self.client_certificate.is_none() => {
+                metrics::ACTIVE_HTTP_CONNECTIONS.dec();
+                metrics::HTTP_STATUS_CODES.with_label_values(&["403"]).inc();
+                Box::pin(async { Ok(forbidden("403 - Client certificate required")) })
+            }
             Some(pool) => {
-                let client_scheme = self.scheme;
+                let listener = self.listener.clone();
                 let client_address = self.client_address;
+                let client_upgrade =
+                    upgrade::is_upgrade_request(&request).then(|| hyper::upgrade::on(&mut request));
 
                 Box::pin(async move {
                     let start_time = std::time::Instant::now();
@@ -159,29 +231,34 @@ impl Service<Request<Body>> for MainService {
 
                         Ok(response)
                     } else {
-                        let context = algorithms::Context {
+                        pool.retry_policy.budget.deposit();
+                        let timeouts =
+                            timeouts_for(&request, pool.timeouts, &pool.timeout_overrides);
+                        let attempts = Attempts {
+                            pool: &pool,
+                            backend_addresses: working_addresses,
+                            timeouts,
+                            responding_backend: Mutex::new(None),
+                        };
+                        let context = middleware::Context {
+                            client_scheme: &listener.scheme,
+                            listener_port: listener.address.port(),
                             client_address: &client_address,
-                            backend_addresses: &working_addresses,
+                            client_ip,
+                            backend: &attempts,
+                        };
+                        // the middlewares run once, only sending to the backend is retried
+                        let result = pool.chain.forward_request(request, &context).await;
+                        let responding_backend = attempts.responding_backend.lock().unwrap().take();
+                        let result = match (client_upgrade, responding_backend) {
+                            (Some(client_upgrade), Some(backend_address)) => upgrade::bridge(
+                                client_upgrade,
+                                result,
+                                backend_address,
+                                timeouts.upgrade_idle,
+                            ),
+                            _ => result,
                         };
-                        let backend = pool.strategy.select_backend(&request, &context);
-
-                        let backend_start = std::time::Instant::now();
-                        let result = backend
-                            .forward_request_to_backend(
-                                request,
-                                &pool.chain,
-                                &client_scheme,
-                                &client_address,
-                                &pool.client,
-                            )
-                            .await;
-
-                        // Track backend response time
-                        let backend_duration = backend_start.elapsed().as_secs_f64();
-                        let backend_addr = backend.backend_address;
-                        metrics::BACKEND_RESPONSE_TIME
-                            .with_label_values(&[backend_addr])
-                            .observe(backend_duration);
 
                         // Track status code
                         let status = result.status();
@@ -213,38 +290,213 @@ impl Service<Request<Body>> for MainService {
     }
 }
 
+/// Sends requests which passed the middleware chain of `pool` to one of the
+/// `backend_addresses`, and again to another one if the pool's
+/// [`RetryPolicy`] says so.
+struct Attempts<'l> {
+    pool: &'l BackendPool,
+    backend_addresses: Vec<&'l str>,
+    timeouts: Timeouts,
+    /// The backend which sent the final response, `None` until one was contacted
+    responding_backend: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl SendToBackend for Attempts<'_> {
+    async fn send_to_backend(
+        &self,
+        request: Request<Body>,
+        context: &middleware::Context<'_>,
+    ) -> Response<Body> {
+        let retry_policy = &self.pool.retry_policy;
+        let (mut request, replayable) = if retry_policy.enabled() {
+            match buffer_for_retries(request).await {
+                Ok(buffered) => buffered,
+                Err(response) => return response,
+            }
+        } else {
+            (request, None)
+        };
+
+        let mut failed_addresses: Vec<String> = Vec::new();
+        let mut attempt = 1;
+        loop {
+            // retries go to a different backend as long as there is one left
+            let mut candidates = self
+                .backend_addresses
+                .iter()
+                .filter(|address| !failed_addresses.iter().any(|it| it == *address))
+                .copied()
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                candidates = self.backend_addresses.clone();
+            }
+            let lb_context = algorithms::Context {
+                client_address: context.client_address,
+                client_ip: context.client_ip,
+                backend_addresses: &candidates,
+            };
+            let backend = self.pool.strategy.select_backend(&request, &lb_context);
+            let method = request.method().clone();
+
+            let backend_start = std::time::Instant::now();
+            let result = backend
+                .forward_request_to_backend(request, self.pool, context, self.timeouts)
+                .await;
+
+            // Track backend response time
+            let backend_duration = backend_start.elapsed().as_secs_f64();
+            let backend_addr = backend.backend_address;
+            metrics::BACKEND_RESPONSE_TIME
+                .with_label_values(&[backend_addr])
+                .observe(backend_duration);
+
+            let retry = replayable
+                .as_ref()
+                .filter(|_| candidates.len() > 1)
+                .and_then(|replayable| {
+                    retry_policy
+                        .should_retry(&method, attempt, &result)
+                        .map(|condition| (condition, replayable))
+                });
+            match retry {
+                Some((condition, replayable)) => {
+                    debug!(
+                        "Retrying request to {} on another backend ({})",
+                        backend_addr,
+                        condition.label()
+                    );
+                    metrics::RETRIES_TOTAL
+                        .with_label_values(&[condition.label()])
+                        .inc();
+                    failed_addresses.push(backend_addr.to_string());
+                    request = replayable.to_request();
+                    attempt += 1;
+                }
+                None => {
+                    *self.responding_backend.lock().unwrap() = Some(backend_addr.to_string());
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+/// Requests with larger (or unknown) bodies are not buffered and thus not retried.
+const MAX_REPLAYABLE_BODY_SIZE: u64 = 64 * 1024;
+
+/// A buffered copy of a request, which can be sent again when retrying it on
+/// another backend.
+struct ReplayableRequest {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    client_certificate: Option<Arc<ClientCertificate>>,
+    body: Bytes,
+}
+
+impl ReplayableRequest {
+    fn to_request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::from(self.body.clone()));
+        *request.method_mut() = self.method.clone();
+        *request.uri_mut() = self.uri.clone();
+        *request.version_mut() = self.version;
+        *request.headers_mut() = self.headers.clone();
+        if let Some(client_certificate) = &self.client_certificate {
+            request.extensions_mut().insert(client_certificate.clone());
+        }
+        request
+    }
+}
+
+/// Buffers the body of `request` if it is small enough to be retried, returning
+/// the request to send first and, if buffered, a copy to send on retries.
+#[allow(clippy::result_large_err)]
+async fn buffer_for_retries(
+    request: Request<Body>,
+) -> Result<(Request<Body>, Option<ReplayableRequest>), Response<Body>> {
+    let size = request.body().size_hint().upper();
+    if size.is_none_or(|size| size > MAX_REPLAYABLE_BODY_SIZE) {
+        return Ok((request, None));
+    }
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|_| bad_request("Failed to read request body"))?;
+    let replayable = ReplayableRequest {
+        method: parts.method.clone(),
+        uri: parts.uri.clone(),
+        version: parts.version,
+        headers: parts.headers.clone(),
+        client_certificate: parts.extensions.get().cloned(),
+        body: body.clone(),
+    };
+    Ok((
+        Request::from_parts(parts, Body::from(body)),
+        Some(replayable),
+    ))
+}
+
 fn pool_by_req(
     shared_data: &SharedData,
     request: &Request<Body>,
-    scheme: &Scheme,
+    listener: &Listener,
 ) -> Option<Arc<BackendPool>> {
     shared_data
         .backend_pools
         .iter()
-        .filter(|pool| pool.supports(scheme))
+        .filter(|pool| pool.accepts(listener))
         .find(|pool| pool.matcher.matches(request))
         .cloned()
 }
 
+#[derive(Clone)]
 pub struct SharedData {
     pub backend_pools: Vec<Arc<BackendPool>>,
+    pub trusted_proxies: TrustedProxies,
     pub acme_handler: Arc<AcmeHandler>,
+    pub client_certificate_headers: ClientCertificateHeaders,
 }
 
 #[derive(Debug)]
 pub struct BackendPool {
     pub matcher: BackendPoolMatcher,
-    pub addresses: Vec<(String, ArcSwap<Healthiness>)>,
+    pub addresses: Vec<(String, Arc<ArcSwap<Healthiness>>)>,
     pub health_config: HealthConfig,
+    pub strategy_config: LoadBalancingStrategyConfig,
     pub strategy: Arc<Box<dyn LoadBalancingStrategy>>,
     pub chain: MiddlewareChain,
+    pub client_settings: ClientSettings,
     pub client: Client<StrategyNotifyHttpConnector, Body>,
+    /// TLS config for connections to the backend servers, `None` if they are
+    /// contacted via plain HTTP
+    pub tls_config: Option<Arc<ClientConfig>>,
     pub schemes: HashSet<Scheme>,
+    /// Names of the listeners this pool serves, `None` serves all.
+    pub listeners: Option<HashSet<String>>,
+    pub retry_policy: RetryPolicy,
+    pub timeouts: Timeouts,
+    pub timeout_overrides: Vec<TimeoutOverride>,
+    /// Only serve clients which authenticated with a verified certificate.
+    pub client_certificate_required: bool,
 }
 
 impl BackendPool {
-    fn supports(&self, scheme: &Scheme) -> bool {
-        self.schemes.contains(scheme)
+    fn accepts(&self, listener: &Listener) -> bool {
+        self.schemes.contains(&listener.scheme)
+            && self
+                .listeners
+                .as_ref()
+                .is_none_or(|listeners| listeners.contains(&listener.name))
+    }
+
+    /// The scheme used to contact the backend servers.
+    pub fn backend_scheme(&self) -> Scheme {
+        match self.tls_config {
+            Some(_) => Scheme::HTTPS,
+            None => Scheme::HTTP,
+        }
     }
 }
 
@@ -254,23 +506,43 @@ impl PartialEq for BackendPool {
     }
 }
 
+/// Settings of the hyper [`Client`] used to talk to the backend servers of a
+/// [`BackendPool`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientSettings {
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub connect_timeout: Option<Duration>,
+    pub upstream_tls: Option<UpstreamTls>,
+    /// Announce the client address to the backend servers via PROXY protocol.
+    pub send_proxy_protocol: bool,
+    pub protocol: BackendProtocol,
+    pub http2: Http2Settings,
+}
+
 pub struct BackendPoolBuilder {
     matcher: BackendPoolMatcher,
-    addresses: Vec<(String, ArcSwap<Healthiness>)>,
+    addresses: Vec<String>,
     health_config: HealthConfig,
-    strategy: Box<dyn LoadBalancingStrategy>,
+    strategy_config: LoadBalancingStrategyConfig,
     chain: MiddlewareChain,
     schemes: HashSet<Scheme>,
-    pool_idle_timeout: Option<Duration>,
-    pool_max_idle_per_host: Option<usize>,
+    listeners: Option<HashSet<String>>,
+    client_settings: ClientSettings,
+    tls_config: Option<Arc<ClientConfig>>,
+    retry_policy: RetryPolicy,
+    timeouts: Timeouts,
+    timeout_overrides: Vec<TimeoutOverride>,
+    client_certificate_required: bool,
+    previous: Option<Arc<BackendPool>>,
 }
 
 impl BackendPoolBuilder {
     pub fn new(
         matcher: BackendPoolMatcher,
-        addresses: Vec<(String, ArcSwap<Healthiness>)>,
+        addresses: Vec<String>,
         health_config: HealthConfig,
-        strategy: Box<dyn LoadBalancingStrategy>,
+        strategy_config: LoadBalancingStrategyConfig,
         chain: MiddlewareChain,
         schemes: HashSet<Scheme>,
     ) -> BackendPoolBuilder {
@@ -278,45 +550,194 @@ impl BackendPoolBuilder {
             matcher,
             addresses,
             health_config,
-            strategy,
+            strategy_config,
             chain,
             schemes,
-            pool_idle_timeout: None,
-            pool_max_idle_per_host: None,
+            listeners: None,
+            client_settings: ClientSettings::default(),
+            tls_config: None,
+            retry_policy: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            timeout_overrides: Vec::new(),
+            client_certificate_required: false,
+            previous: None,
         }
     }
 
+    /// Only serve requests received by the listeners with these names.
+    pub fn listeners(&mut self, listeners: HashSet<String>) -> &BackendPoolBuilder {
+        self.listeners = Some(listeners);
+        self
+    }
+
     pub fn pool_idle_timeout(&mut self, duration: Duration) -> &BackendPoolBuilder {
-        self.pool_idle_timeout = Some(duration);
+        self.client_settings.pool_idle_timeout = Some(duration);
         self
     }
 
     pub fn pool_max_idle_per_host(&mut self, max_idle: usize) -> &BackendPoolBuilder {
-        self.pool_max_idle_per_host = Some(max_idle);
+        self.client_settings.pool_max_idle_per_host = Some(max_idle);
+        self
+    }
+
+    pub fn connect_timeout(&mut self, duration: Duration) -> &BackendPoolBuilder {
+        self.client_settings.connect_timeout = Some(duration);
+        self
+    }
+
+    pub fn protocol(&mut self, protocol: BackendProtocol) -> &BackendPoolBuilder {
+        self.client_settings.protocol = protocol;
+        self
+    }
+
+    pub fn http2(&mut self, http2: Http2Settings) -> &BackendPoolBuilder {
+        self.client_settings.http2 = http2;
+        self
+    }
+
+    /// Start every connection to the backend servers with a PROXY protocol
+    /// header. Idle connections are not reused, because they belong to the
+    /// client which caused them to be opened.
+    pub fn send_proxy_protocol(&mut self) -> &BackendPoolBuilder {
+        self.client_settings.send_proxy_protocol = true;
+        self
+    }
+
+    /// Contact the backend servers via HTTPS using `tls_config`, which was
+    /// created from `upstream_tls`.
+    pub fn upstream_tls(
+        &mut self,
+        upstream_tls: UpstreamTls,
+        tls_config: ClientConfig,
+    ) -> &BackendPoolBuilder {
+        self.client_settings.upstream_tls = Some(upstream_tls);
+        self.tls_config = Some(Arc::new(tls_config));
+        self
+    }
+
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &BackendPoolBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn timeout_override(&mut self, timeout_override: TimeoutOverride) -> &BackendPoolBuilder {
+        self.timeout_overrides.push(timeout_override);
+        self
+    }
+
+    pub fn retry_policy(&mut self, retry_policy: RetryPolicy) -> &BackendPoolBuilder {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Answer requests of clients without a verified certificate, see
+    /// [`ClientAuth`](crate::tls::ClientAuth), with 403 Forbidden.
+    pub fn require_client_certificate(&mut self) -> &BackendPoolBuilder {
+        self.client_certificate_required = true;
+        self
+    }
+
+    /// Carry over the state of `previous`, the pool this one replaces after a
+    /// config reload. The healthiness of addresses present in both pools is
+    /// kept, as well as the tokens left in the retry budget. The strategy (e.g.
+    /// round robin counters and connection counts) is
+    /// kept if it did not change, the client with its idle connections if the
+    /// client settings did not change either. Clients contacting the backend
+    /// servers via HTTPS are always replaced, since the files of their TLS
+    /// settings may have been rotated in place.
+    pub fn previous(&mut self, previous: Arc<BackendPool>) -> &BackendPoolBuilder {
+        self.previous = Some(previous);
         self
     }
 
     pub fn build(self) -> BackendPool {
-        let mut client_builder = Client::builder();
-        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
-            client_builder.pool_idle_timeout(pool_idle_timeout);
-        }
-        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
-            client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        if let Some(previous) = &self.previous {
+            self.retry_policy
+                .budget
+                .take_over(&previous.retry_policy.budget);
         }
+        let addresses = self
+            .addresses
+            .into_iter()
+            .map(|address| {
+                let healthiness = self
+                    .previous
+                    .iter()
+                    .flat_map(|previous| previous.addresses.iter())
+                    .find(|(previous_address, _)| *previous_address == address)
+                    .map(|(_, healthiness)| healthiness.clone())
+                    .unwrap_or_else(|| Arc::new(ArcSwap::from_pointee(Healthiness::Healthy)));
+                (address, healthiness)
+            })
+            .collect();
 
-        let strategy = Arc::new(self.strategy);
-        let client: Client<_, Body> =
-            client_builder.build(StrategyNotifyHttpConnector::new(strategy.clone()));
+        let previous = self
+            .previous
+            .filter(|previous| previous.strategy_config == self.strategy_config);
+        let reusable = previous.as_ref().filter(|previous| {
+            previous.client_settings == self.client_settings
+                && self.client_settings.upstream_tls.is_none()
+        });
+        let (strategy, client) = match reusable {
+            Some(previous) => (previous.strategy.clone(), previous.client.clone()),
+            None => {
+                let mut client_builder = Client::builder();
+                if let Some(pool_idle_timeout) = self.client_settings.pool_idle_timeout {
+                    client_builder.pool_idle_timeout(pool_idle_timeout);
+                }
+                if let Some(pool_max_idle_per_host) = self.client_settings.pool_max_idle_per_host {
+                    client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+                }
+                if self.client_settings.send_proxy_protocol {
+                    client_builder.pool_max_idle_per_host(0);
+                }
+                if self.client_settings.protocol == BackendProtocol::H2c {
+                    client_builder.http2_only(true);
+                }
+                self.client_settings.http2.apply_to(&mut client_builder);
+
+                let strategy: Arc<Box<dyn LoadBalancingStrategy>> = match &previous {
+                    Some(previous) => previous.strategy.clone(),
+                    None => Arc::new(self.strategy_config.clone().into()),
+                };
+                let tls_config = match &self.tls_config {
+                    Some(tls_config) => tls_config.as_ref().clone(),
+                    None => plain_client_config(),
+                };
+                let server_name = self
+                    .client_settings
+                    .upstream_tls
+                    .as_ref()
+                    .and_then(|it| it.server_name.clone());
+                let connector = StrategyNotifyHttpConnector::new(
+                    strategy.clone(),
+                    self.client_settings.connect_timeout,
+                    self.client_settings.protocol,
+                    self.client_settings.send_proxy_protocol,
+                    tls_config,
+                    server_name,
+                );
+                let client: Client<_, Body> = client_builder.build(connector);
+                (strategy, client)
+            }
+        };
 
         BackendPool {
             matcher: self.matcher,
-            addresses: self.addresses,
+            addresses,
             health_config: self.health_config,
+            strategy_config: self.strategy_config,
             strategy,
             chain: self.chain,
+            client_settings: self.client_settings,
             client,
+            tls_config: self.tls_config,
             schemes: self.schemes,
+            listeners: self.listeners,
+            retry_policy: self.retry_policy,
+            timeouts: self.timeouts,
+            timeout_overrides: self.timeout_overrides,
+            client_certificate_required: self.client_certificate_required,
         }
     }
 }
@@ -341,42 +762,62 @@ impl Display for Scheme {
 mod tests {
 
     use super::*;
-    use crate::algorithms::random::Random;
+    use crate::tls::{SessionResumption, TlsPolicy};
     use std::{collections::HashMap, iter::FromIterator};
 
     fn generate_config(shared_data: SharedData) -> RuntimeConfig {
         RuntimeConfig {
             shared_data,
-            http_address: "0.0.0.0:80".parse().unwrap(),
-            https_address: "0.0.0.0:443".parse().unwrap(),
+            listeners: vec![generate_listener("http", Scheme::HTTP)],
             certificates: HashMap::new(),
-            health_interval: std::time::Duration::from_secs(60),
+            certificate_files: HashMap::new(),
+            acme_certificates: HashMap::new(),
+            ocsp_sources: HashMap::new(),
+            default_certificate: None,
+        }
+    }
+    fn generate_listener(name: &str, scheme: Scheme) -> Listener {
+        Listener {
+            name: name.into(),
+            address: "0.0.0.0:80".parse().unwrap(),
+            scheme,
+            certificates: None,
+            proxy_protocol: None,
+            alpn_protocols: Vec::new(),
+            client_auth: None,
+            tls_policy: TlsPolicy::default(),
+            session_resumption: SessionResumption::default(),
+            handshake_timeout: Duration::from_secs(10),
+            max_concurrent_handshakes: 1024,
         }
     }
     fn generate_test_service(host: String, scheme: Scheme) -> MainService {
         MainService {
-            scheme,
+            listener: Arc::new(generate_listener("http", scheme)),
+            alpn_protocol: None,
+            client_certificate: None,
             client_address: "127.0.0.1:3000".parse().unwrap(),
             config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
                 backend_pools: vec![Arc::new(
                     BackendPoolBuilder::new(
                         BackendPoolMatcher::Host(host),
-                        vec![(
-                            "127.0.0.1:8084".into(),
-                            ArcSwap::from_pointee(Healthiness::Healthy),
-                        )],
+                        vec!["127.0.0.1:8084".into()],
                         HealthConfig {
                             slow_threshold: 200,
                             timeout: 500,
                             path: String::from("/"),
+                            interval: 10_000,
+                            jitter: 0,
                         },
-                        Box::new(Random::new()),
+                        LoadBalancingStrategyConfig::Random,
                         MiddlewareChain::Empty,
                         HashSet::from_iter(vec![Scheme::HTTP]),
                     )
                     .build(),
                 )],
+                trusted_proxies: TrustedProxies::default(),
                 acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
             }))),
         }
     }
@@ -391,7 +832,7 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let pool = pool_by_req(shared_data, &request, &service.scheme);
+        let pool = pool_by_req(shared_data, &request, &service.listener);
 
         assert_eq!(pool, None);
     }
@@ -406,8 +847,331 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let pool = pool_by_req(shared_data, &request, &service.scheme);
+        let pool = pool_by_req(shared_data, &request, &service.listener);
 
         assert_eq!(pool, Some(shared_data.backend_pools[0].clone()));
     }
+
+    #[test]
+    fn pool_by_req_respects_pool_listeners() {
+        let mut builder = generate_pool_builder(
+            vec!["127.0.0.1:1".into()],
+            LoadBalancingStrategyConfig::RoundRobin,
+        );
+        builder.listeners(HashSet::from_iter(vec!["internal".to_string()]));
+        let shared_data = SharedData {
+            backend_pools: vec![Arc::new(builder.build())],
+            trusted_proxies: TrustedProxies::default(),
+            acme_handler: Arc::new(AcmeHandler::new()),
+            client_certificate_headers: Default::default(),
+        };
+        let request = Request::builder()
+            .header("host", "whoami.localhost")
+            .body(Body::empty())
+            .unwrap();
+
+        let internal = generate_listener("internal", Scheme::HTTP);
+        let public = generate_listener("public", Scheme::HTTP);
+
+        assert!(pool_by_req(&shared_data, &request, &internal).is_some());
+        assert!(pool_by_req(&shared_data, &request, &public).is_none());
+    }
+
+    fn generate_pool_builder(
+        addresses: Vec<String>,
+        strategy_config: LoadBalancingStrategyConfig,
+    ) -> BackendPoolBuilder {
+        BackendPoolBuilder::new(
+            BackendPoolMatcher::Host("whoami.localhost".into()),
+            addresses,
+            HealthConfig {
+                slow_threshold: 200,
+                timeout: 500,
+                path: String::from("/"),
+                interval: 10_000,
+                jitter: 0,
+            },
+            strategy_config,
+            MiddlewareChain::Empty,
+            HashSet::from_iter(vec![Scheme::HTTP]),
+        )
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_certificate_if_the_pool_requires_one() {
+        let mut builder = generate_pool_builder(
+            vec!["127.0.0.1:1".into()],
+            LoadBalancingStrategyConfig::RoundRobin,
+        );
+        builder.require_client_certificate();
+        let mut service = MainService {
+            listener: Arc::new(generate_listener("http", Scheme::HTTP)),
+            alpn_protocol: None,
+            client_certificate: None,
+            client_address: "127.0.0.1:3000".parse().unwrap(),
+            config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+                backend_pools: vec![Arc::new(builder.build())],
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            }))),
+        };
+        let request = Request::builder()
+            .header("host", "whoami.localhost")
+            .header("x-client-cert-subject", "CN=spoofed")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = service.call(request).await.unwrap();
+
+        assert_eq!(response.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn routes_http2_requests_by_their_authority() {
+        let backend =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(
+                    |request: Request<Body>| async move {
+                        let host = request.headers().get(HOST).cloned();
+                        Ok::<_, hyper::Error>(Response::new(Body::from(
+                            host.map(|it| it.as_bytes().to_vec()).unwrap_or_default(),
+                        )))
+                    },
+                ))
+            }));
+        let address = backend.local_addr();
+        tokio::spawn(backend);
+
+        let builder = generate_pool_builder(
+            vec![address.to_string()],
+            LoadBalancingStrategyConfig::RoundRobin,
+        );
+        let mut service = MainService {
+            listener: Arc::new(generate_listener("http", Scheme::HTTP)),
+            alpn_protocol: Some("h2".into()),
+            client_certificate: None,
+            client_address: "127.0.0.1:3000".parse().unwrap(),
+            config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+                backend_pools: vec![Arc::new(builder.build())],
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            }))),
+        };
+        // as received by hyper from an HTTP/2 client, without a Host header
+        let request = Request::builder()
+            .version(Version::HTTP_2)
+            .uri("https://whoami.localhost/path")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = service.call(request).await.unwrap();
+
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "whoami.localhost");
+    }
+
+    #[test]
+    fn build_with_previous_keeps_healthiness_and_strategy() {
+        let previous = Arc::new(
+            generate_pool_builder(
+                vec!["127.0.0.1:1".into(), "127.0.0.1:2".into()],
+                LoadBalancingStrategyConfig::RoundRobin,
+            )
+            .build(),
+        );
+        previous.addresses[0]
+            .1
+            .store(Arc::new(Healthiness::Unresponsive(None)));
+
+        let mut builder = generate_pool_builder(
+            vec!["127.0.0.1:1".into(), "127.0.0.1:3".into()],
+            LoadBalancingStrategyConfig::RoundRobin,
+        );
+        builder.previous(previous.clone());
+        let pool = builder.build();
+
+        assert_eq!(
+            pool.addresses[0].1.load().as_ref(),
+            &Healthiness::Unresponsive(None)
+        );
+        assert_eq!(pool.addresses[1].1.load().as_ref(), &Healthiness::Healthy);
+        assert!(Arc::ptr_eq(&pool.strategy, &previous.strategy));
+    }
+
+    #[test]
+    fn build_with_previous_replaces_changed_strategy() {
+        let previous = Arc::new(
+            generate_pool_builder(
+                vec!["127.0.0.1:1".into()],
+                LoadBalancingStrategyConfig::RoundRobin,
+            )
+            .build(),
+        );
+
+        let mut builder = generate_pool_builder(
+            vec!["127.0.0.1:1".into()],
+            LoadBalancingStrategyConfig::LeastConnection,
+        );
+        builder.previous(previous.clone());
+        let pool = builder.build();
+
+        assert!(!Arc::ptr_eq(&pool.strategy, &previous.strategy));
+        assert!(Arc::ptr_eq(&pool.addresses[0].1, &previous.addresses[0].1));
+    }
+
+    #[tokio::test]
+    async fn build_with_previous_picks_up_rotated_upstream_tls_files() {
+        let (config, (certificate_path, private_key_path)) =
+            crate::tls::tests::client_auth_config(true);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(crate::tls::server_config(
+            Arc::new(ArcSwap::from_pointee(config.clone())),
+            &config.listeners[0],
+        )));
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = backend.accept().await {
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(
+                    stream,
+                    hyper::service::service_fn(|_| async {
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }),
+                ));
+            }
+        });
+        let upstream_tls = UpstreamTls {
+            ca_bundle_path: None,
+            server_name: Some("localhost".into()),
+            client_certificate: Some((certificate_path.clone(), private_key_path.clone())),
+            insecure_skip_verify: true,
+        };
+        let build = |previous: Option<Arc<BackendPool>>| {
+            let mut builder = generate_pool_builder(
+                vec![address.to_string()],
+                LoadBalancingStrategyConfig::RoundRobin,
+            );
+            builder.upstream_tls(upstream_tls.clone(), upstream_tls.client_config().unwrap());
+            if let Some(previous) = previous {
+                builder.previous(previous);
+            }
+            Arc::new(builder.build())
+        };
+        let request = |pool: Arc<BackendPool>| async move {
+            pool.client
+                .get(format!("https://{}/", address).parse().unwrap())
+                .await
+        };
+        let previous = build(None);
+        assert!(request(previous.clone()).await.is_ok());
+
+        // replaced by a certificate the backend does not trust
+        let (other_certificate, other_key) = crate::tls::tests::write_self_signed("client");
+        std::fs::copy(other_certificate, &certificate_path).unwrap();
+        std::fs::copy(other_key, &private_key_path).unwrap();
+        let pool = build(Some(previous.clone()));
+
+        assert!(request(pool.clone()).await.is_err());
+        assert!(Arc::ptr_eq(&pool.strategy, &previous.strategy));
+    }
+
+    #[tokio::test]
+    async fn runs_middlewares_once_per_retried_request() {
+        let backend =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(|_| async {
+                    Ok::<_, hyper::Error>(Response::new(Body::from("alive")))
+                }))
+            }));
+        let address = backend.local_addr();
+        tokio::spawn(backend);
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // round robin tries the closed address first
+        let mut builder = BackendPoolBuilder::new(
+            BackendPoolMatcher::Host("whoami.localhost".into()),
+            vec![closed.to_string(), address.to_string()],
+            HealthConfig {
+                slow_threshold: 200,
+                timeout: 500,
+                path: String::from("/"),
+                interval: 10_000,
+                jitter: 0,
+            },
+            LoadBalancingStrategyConfig::RoundRobin,
+            MiddlewareChain::Entry {
+                middleware: Box::new(crate::middleware::rate_limiter::RateLimiter::new(1, 60)),
+                chain: Box::new(MiddlewareChain::Empty),
+            },
+            HashSet::from_iter(vec![Scheme::HTTP]),
+        );
+        builder.retry_policy(RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::default()
+        });
+        let mut service = MainService {
+            listener: Arc::new(generate_listener("http", Scheme::HTTP)),
+            alpn_protocol: None,
+            client_certificate: None,
+            client_address: "127.0.0.1:3000".parse().unwrap(),
+            config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+                backend_pools: vec![Arc::new(builder.build())],
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            }))),
+        };
+        let request = Request::builder()
+            .header(HOST, "whoami.localhost")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = service.call(request).await.unwrap();
+
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "alive");
+    }
+
+    #[tokio::test]
+    async fn h2c_pool_speaks_http2_with_prior_knowledge() {
+        let backend = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(
+                    |request: Request<Body>| async move {
+                        Ok::<_, hyper::Error>(Response::new(Body::from(format!(
+                            "{:?}",
+                            request.version()
+                        ))))
+                    },
+                ))
+            }));
+        let address = backend.local_addr();
+        tokio::spawn(backend);
+
+        let mut builder = generate_pool_builder(
+            vec![address.to_string()],
+            LoadBalancingStrategyConfig::Random,
+        );
+        builder.protocol(BackendProtocol::H2c);
+        let pool = builder.build();
+        let response = pool
+            .client
+            .get(format!("http://{}/", address).parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.version(), Version::HTTP_2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "HTTP/2.0");
+    }
 }
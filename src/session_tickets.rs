@@ -0,0 +1,257 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    rand::rand_bytes,
+    sha::sha256,
+    sign::Signer,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use std::{
+    fs,
+    io::{self, ErrorKind::InvalidData},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_rustls::rustls::server::ProducesTickets;
+
+/// How long clients may use a ticket, the same as with the rotating keys of
+/// rustls.
+const TICKET_LIFETIME_SECONDS: u32 = 12 * 60 * 60;
+/// How often the key file is read again to pick up rotated keys.
+const TICKET_KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+const KEY_NAME_LENGTH: usize = 8;
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// A session ticket key shared by RustStrom instances.
+#[derive(Clone)]
+pub struct TicketKey {
+    /// Identifies the key a ticket was encrypted with.
+    name: [u8; KEY_NAME_LENGTH],
+    key: [u8; KEY_LENGTH],
+}
+
+impl TicketKey {
+    fn new(key: [u8; KEY_LENGTH]) -> TicketKey {
+        let mut name = [0; KEY_NAME_LENGTH];
+        name.copy_from_slice(&sha256(&key)[..KEY_NAME_LENGTH]);
+        TicketKey { name, key }
+    }
+
+    /// The key for tickets issued in `context`, so that they can not be used
+    /// in another one.
+    fn derive(&self, context: &[u8]) -> io::Result<TicketKey> {
+        let hmac_key = PKey::hmac(&self.key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key)?;
+        signer.update(b"RustStrom session ticket key")?;
+        signer.update(context)?;
+        let mut key = [0; KEY_LENGTH];
+        key.copy_from_slice(&signer.sign_to_vec()?[..KEY_LENGTH]);
+        Ok(TicketKey::new(key))
+    }
+}
+
+/// Reads the session ticket keys in `path`, one base64 encoded 32 byte key
+/// per line (e.g. from `openssl rand -base64 32`). New tickets are encrypted
+/// with the first key, the others only decrypt tickets issued before a
+/// rotation. Empty lines and lines starting with `#` are ignored.
+pub fn load_ticket_keys<P: AsRef<Path>>(path: P) -> io::Result<Vec<TicketKey>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Could not read '{}': {}", path.display(), e),
+        )
+    })?;
+    let keys = content
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            STANDARD
+                .decode(line)
+                .ok()
+                .and_then(|key| <[u8; KEY_LENGTH]>::try_from(key).ok())
+                .map(TicketKey::new)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        InvalidData,
+                        format!(
+                            "Line {} of '{}' is no base64 encoded {} byte key",
+                            index + 1,
+                            path.display(),
+                            KEY_LENGTH
+                        ),
+                    )
+                })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        return Err(io::Error::new(
+            InvalidData,
+            format!("'{}' contains no session ticket key", path.display()),
+        ));
+    }
+    Ok(keys)
+}
+
+/// Encrypts session tickets with AES-256-GCM and the keys of a file shared by
+/// RustStrom instances, so that they resume each other's sessions. The file is
+/// read again every [`TICKET_KEY_RELOAD_INTERVAL`], the previous keys are kept
+/// while it is invalid.
+///
+/// rustls does not verify client certificates again when resuming a session,
+/// so the keys are derived from a `context` of the listener and its client
+/// certificate settings. Tickets of another listener, e.g. one without client
+/// authentication, or from before a CA rotation, do not resume.
+pub struct SharedTicketer {
+    path: PathBuf,
+    context: Vec<u8>,
+    keys: Mutex<(Instant, Arc<Vec<TicketKey>>)>,
+}
+
+impl SharedTicketer {
+    pub fn new(path: PathBuf, context: Vec<u8>) -> io::Result<SharedTicketer> {
+        let keys = derive_keys(load_ticket_keys(&path)?, &context)?;
+        Ok(SharedTicketer {
+            path,
+            context,
+            keys: Mutex::new((Instant::now(), Arc::new(keys))),
+        })
+    }
+
+    fn keys(&self) -> Arc<Vec<TicketKey>> {
+        let mut keys = self.keys.lock().unwrap();
+        let (loaded_at, current) = &mut *keys;
+        if loaded_at.elapsed() >= TICKET_KEY_RELOAD_INTERVAL {
+            *loaded_at = Instant::now();
+            match load_ticket_keys(&self.path).and_then(|it| derive_keys(it, &self.context)) {
+                Ok(reloaded) => {
+                    if reloaded.first().map(|it| it.name) != current.first().map(|it| it.name) {
+                        info!("Rotated session ticket key from '{}'", self.path.display());
+                    }
+                    *current = Arc::new(reloaded);
+                }
+                Err(e) => warn!("Keeping the previous session ticket keys: {}", e),
+            }
+        }
+        current.clone()
+    }
+}
+
+fn derive_keys(keys: Vec<TicketKey>, context: &[u8]) -> io::Result<Vec<TicketKey>> {
+    keys.iter().map(|key| key.derive(context)).collect()
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        TICKET_LIFETIME_SECONDS
+    }
+
+    /// The ticket is the key name, the nonce, the ciphertext and the tag.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        let key = keys.first()?;
+        let mut nonce = [0; NONCE_LENGTH];
+        rand_bytes(&mut nonce).ok()?;
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.key,
+            Some(&nonce),
+            &key.name,
+            plain,
+            &mut tag,
+        )
+        .ok()?;
+        Some([&key.name[..], &nonce, &ciphertext, &tag].concat())
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < KEY_NAME_LENGTH + NONCE_LENGTH + TAG_LENGTH {
+            return None;
+        }
+        let (name, rest) = ticket.split_at(KEY_NAME_LENGTH);
+        let (nonce, rest) = rest.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+        let keys = self.keys();
+        let key = keys.iter().find(|it| it.name == name)?;
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.key,
+            Some(nonce),
+            &key.name,
+            ciphertext,
+            tag,
+        )
+        .ok()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    pub fn write_ticket_keys(name: &str, keys: &[[u8; KEY_LENGTH]]) -> PathBuf {
+        let path = temp_dir().join(format!(
+            "rust-strom-ticket-keys-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let content = keys
+            .iter()
+            .map(|key| STANDARD.encode(key))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&path, format!("# rotated daily\n{}\n", content)).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_ticket_keys() {
+        let path = write_ticket_keys("load", &[[1; 32], [2; 32]]);
+        let keys = load_ticket_keys(&path).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key, [1; 32]);
+
+        fs::write(&path, STANDARD.encode([1; 16])).unwrap();
+        assert!(load_ticket_keys(&path).is_err());
+        fs::write(&path, "# no keys yet\n").unwrap();
+        assert!(load_ticket_keys(&path).is_err());
+        assert!(load_ticket_keys(path.with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn decrypts_tickets_of_previous_keys() {
+        let ticketer = |name, keys: &[[u8; 32]], context: &[u8]| {
+            SharedTicketer::new(write_ticket_keys(name, keys), context.to_vec()).unwrap()
+        };
+        let old = ticketer("old", &[[1; 32]], b"public");
+        let rotated = ticketer("rotated", &[[2; 32], [1; 32]], b"public");
+        let other = ticketer("other", &[[3; 32]], b"public");
+        let other_context = ticketer("other-context", &[[1; 32]], b"partners");
+
+        let ticket = old.encrypt(b"session").unwrap();
+        assert_eq!(old.decrypt(&ticket), Some(b"session".to_vec()));
+        assert_eq!(rotated.decrypt(&ticket), Some(b"session".to_vec()));
+        assert_eq!(other.decrypt(&ticket), None);
+        assert_eq!(other_context.decrypt(&ticket), None);
+        assert_eq!(old.decrypt(&rotated.encrypt(b"session").unwrap()), None);
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(old.decrypt(&tampered), None);
+        assert_eq!(old.decrypt(&ticket[..20]), None);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{backend_pool_matcher::BackendPoolMatcher, metrics};
+use hyper::{body::HttpBody, Body, Request};
+use log::warn;
+use std::{fmt, time::Duration};
+use tokio::time::{timeout, Instant};
+
+/// The timeouts applied to requests forwarded to the backend servers of a
+/// [`BackendPool`](crate::server::BackendPool). The connect timeout is part of
+/// the [`ClientSettings`](crate::server::ClientSettings) instead, because it is
+/// used by the pool's client for all requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time until the response headers of an attempt arrived from the backend.
+    pub response_header: Option<Duration>,
+    /// Time until the response body was received completely.
+    pub total: Option<Duration>,
+    /// Maximum time between two chunks of the response body.
+    pub idle_body: Option<Duration>,
+    /// Maximum time without data in either direction on a connection that was
+    /// upgraded, e.g. to WebSocket.
+    pub upgrade_idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            response_header: Some(Duration::from_secs(30)),
+            total: None,
+            idle_body: None,
+            upgrade_idle: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// Replaces the pool's [`Timeouts`] for all requests matching `matcher`, e.g.
+/// for long polling or report export endpoints.
+#[derive(Debug)]
+pub struct TimeoutOverride {
+    pub matcher: BackendPoolMatcher,
+    pub timeouts: Timeouts,
+}
+
+/// Returns the timeouts of the first override matching `request` or `default`.
+pub fn timeouts_for(
+    request: &Request<Body>,
+    default: Timeouts,
+    overrides: &[TimeoutOverride],
+) -> Timeouts {
+    overrides
+        .iter()
+        .find(|it| it.matcher.matches(request))
+        .map_or(default, |it| it.timeouts)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    ResponseHeader,
+    Total,
+    IdleBody,
+    UpgradeIdle,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutKind::Connect => write!(f, "connect_timeout"),
+            TimeoutKind::ResponseHeader => write!(f, "response_header_timeout"),
+            TimeoutKind::Total => write!(f, "total_timeout"),
+            TimeoutKind::IdleBody => write!(f, "idle_body_timeout"),
+            TimeoutKind::UpgradeIdle => write!(f, "upgrade_idle_timeout"),
+        }
+    }
+}
+
+/// Wraps a response `body` so that it is aborted once the `deadline` passed or
+/// the backend did not send any data for `idle_body_timeout`. Trailers are
+/// passed on unchanged.
+pub fn with_body_timeouts(
+    mut body: Body,
+    backend_address: String,
+    deadline: Option<Instant>,
+    idle_body_timeout: Option<Duration>,
+) -> Body {
+    if (deadline.is_none() && idle_body_timeout.is_none()) || body.is_end_stream() {
+        return body;
+    }
+    let (mut sender, wrapped) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let limit = next_limit(Instant::now(), deadline, idle_body_timeout);
+            let chunk = match limit {
+                Some((limit, kind)) => match timeout(limit, body.data()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        warn!(
+                            "Response body from backend {} aborted, {} exceeded",
+                            backend_address, kind
+                        );
+                        metrics::HTTP_TIMEOUTS_TOTAL.inc();
+                        sender.abort();
+                        return;
+                    }
+                },
+                None => body.data().await,
+            };
+            match chunk {
+                Some(Ok(chunk)) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(_)) => {
+                    sender.abort();
+                    return;
+                }
+                None => break,
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    wrapped
+}
+
+/// The time to wait for the next body chunk and the timeout that fires if it
+/// does not arrive in time.
+fn next_limit(
+    now: Instant,
+    deadline: Option<Instant>,
+    idle_body_timeout: Option<Duration>,
+) -> Option<(Duration, TimeoutKind)> {
+    let total = deadline.map(|it| (it.saturating_duration_since(now), TimeoutKind::Total));
+    let idle = idle_body_timeout.map(|it| (it, TimeoutKind::IdleBody));
+    match (total, idle) {
+        (Some(total), Some(idle)) => Some(if idle.0 < total.0 { idle } else { total }),
+        (total, idle) => total.or(idle),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_limit_prefers_earlier_timeout() {
+        let now = Instant::now();
+        let deadline = Some(now + Duration::from_secs(5));
+
+        assert_eq!(
+            next_limit(now, deadline, Some(Duration::from_secs(1))),
+            Some((Duration::from_secs(1), TimeoutKind::IdleBody))
+        );
+        assert_eq!(
+            next_limit(now, deadline, Some(Duration::from_secs(10))),
+            Some((Duration::from_secs(5), TimeoutKind::Total))
+        );
+        assert_eq!(next_limit(now, None, None), None);
+    }
+
+    #[test]
+    fn timeouts_for_uses_first_matching_override() {
+        let request = Request::builder()
+            .uri("http://localhost/export")
+            .body(Body::empty())
+            .unwrap();
+        let export = Timeouts {
+            total: Some(Duration::from_secs(600)),
+            ..Timeouts::default()
+        };
+        let overrides = vec![
+            TimeoutOverride {
+                matcher: BackendPoolMatcher::Path("/export".into()),
+                timeouts: export,
+            },
+            TimeoutOverride {
+                matcher: BackendPoolMatcher::Path("/export".into()),
+                timeouts: Timeouts::default(),
+            },
+        ];
+
+        assert_eq!(
+            timeouts_for(&request, Timeouts::default(), &overrides),
+            export
+        );
+        assert_eq!(
+            timeouts_for(&request, Timeouts::default(), &[]),
+            Timeouts::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_body_timeout_aborts_body() {
+        let (_sender, body) = Body::channel();
+        let body = with_body_timeouts(
+            body,
+            "127.0.0.1:1".into(),
+            None,
+            Some(Duration::from_millis(10)),
+        );
+
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+}
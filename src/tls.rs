@@ -1,30 +1,39 @@
-use arc_swap::access::Access;
+use crate::{
+    acme::ACME_TLS_ALPN_PROTOCOL, configuration::RuntimeConfig, listeners::Listener, metrics,
+    session_tickets::SharedTicketer,
+};
+use arc_swap::{access::Access, ArcSwap};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use log::{debug, warn};
+use openssl::{
+    asn1::Asn1Time,
+    hash::{hash, MessageDigest},
+    x509::X509,
+};
 use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use std::{
-    collections::HashMap,
+    fmt,
     fs::File,
     io::{self, BufReader, ErrorKind::InvalidData, Seek},
-    path::Path,
+    net::IpAddr,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::SystemTime,
 };
 use tokio_rustls::rustls::{
-    server::{ClientHello, ResolvesServerCert},
+    self,
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+        ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+        ServerSessionMemoryCache,
+    },
     sign::{any_supported_type, CertifiedKey},
-    Certificate, PrivateKey, ServerName,
+    Certificate, CipherSuite, ClientConfig, ConfigBuilder, NamedGroup, PrivateKey, ProtocolVersion,
+    RootCertStore, ServerConfig, ServerName, SignatureScheme, SupportedCipherSuite, Ticketer,
+    WantsVerifier, ALL_CIPHER_SUITES, ALL_KX_GROUPS, ALL_VERSIONS,
 };
 
-pub fn certified_key_from_acme_certificate(
-    certificate: acme_lib::Certificate,
-) -> Result<CertifiedKey, io::Error> {
-    let cert_der_vec = certs(&mut certificate.certificate().as_bytes())
-        .map_err(|_| io::Error::new(InvalidData, "Invalid certificate"))?;
-    let cert_chain: Vec<Certificate> = cert_der_vec.into_iter().map(Certificate).collect();
-    let private_key = PrivateKey(certificate.private_key_der());
-    let signing_key = any_supported_type(&private_key)
-        .map_err(|_| io::Error::new(InvalidData, "Invalid private key"))?;
-    Ok(CertifiedKey::new(cert_chain, signing_key))
-}
-
 pub fn load_certified_key<P1, P2>(
     certificate_path: P1,
     private_key_path: P2,
@@ -33,7 +42,7 @@ where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
-    let certificates = load_certs(certificate_path)?;
+    let certificates = load_certs(&certificate_path)?;
     let private_key = load_key(&private_key_path)?;
     let signing_key = any_supported_type(&private_key).map_err(|_| {
         io::Error::new(
@@ -44,7 +53,146 @@ where
             ),
         )
     })?;
-    Ok(CertifiedKey::new(certificates, signing_key))
+    let certified_key = CertifiedKey::new(certificates, signing_key);
+    if !key_matches_certificate(&certified_key) {
+        return Err(io::Error::new(
+            InvalidData,
+            format!(
+                "Private key '{}' does not belong to certificate '{}'",
+                private_key_path.as_ref().display(),
+                certificate_path.as_ref().display()
+            ),
+        ));
+    }
+    Ok(certified_key)
+}
+
+/// The number of whole days until the end-entity certificate of
+/// `certified_key` expires, negative once it expired. `None` if the
+/// certificate can not be parsed.
+pub fn valid_days_left(certified_key: &CertifiedKey) -> Option<i64> {
+    let certificate = X509::from_der(&certified_key.end_entity_cert().ok()?.0).ok()?;
+    let left = Asn1Time::days_from_now(0)
+        .ok()?
+        .diff(certificate.not_after())
+        .ok()?;
+    Some(left.days.into())
+}
+
+/// The name of `server_name` as used in the config, for logging.
+pub fn server_name_to_string(server_name: &ServerName) -> String {
+    match server_name {
+        ServerName::DnsName(name) => name.as_ref().to_string(),
+        ServerName::IpAddress(ip) => ip.to_string(),
+        _ => format!("{:?}", server_name),
+    }
+}
+
+/// The name a certificate is configured for. A wildcard name like
+/// `*.example.com` matches exactly one further label, e.g. `api.example.com`
+/// but neither `example.com` nor `v1.api.example.com`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CertificateName {
+    Exact(ServerName),
+    /// The name below the wildcard label, `example.com` for `*.example.com`
+    Wildcard(ServerName),
+}
+
+impl CertificateName {
+    /// The certificate names matching the SNI `name`, most specific first.
+    pub fn candidates(name: &str) -> Vec<CertificateName> {
+        let name = name.to_ascii_lowercase();
+        let mut candidates = Vec::new();
+        if let Ok(server_name) = ServerName::try_from(name.as_str()) {
+            candidates.push(CertificateName::Exact(server_name));
+        }
+        if let Some((_, parent)) = name.split_once('.') {
+            if let Ok(server_name @ ServerName::DnsName(_)) = ServerName::try_from(parent) {
+                candidates.push(CertificateName::Wildcard(server_name));
+            }
+        }
+        candidates
+    }
+}
+
+impl TryFrom<&str> for CertificateName {
+    type Error = io::Error;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        let invalid =
+            || io::Error::new(InvalidData, format!("Invalid certificate name '{}'", name));
+        let lowercase = name.to_ascii_lowercase();
+        match lowercase.strip_prefix("*.") {
+            Some(parent) => match ServerName::try_from(parent) {
+                Ok(server_name @ ServerName::DnsName(_)) => {
+                    Ok(CertificateName::Wildcard(server_name))
+                }
+                _ => Err(invalid()),
+            },
+            None => ServerName::try_from(lowercase.as_str())
+                .map(CertificateName::Exact)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for CertificateName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateName::Exact(server_name) => f.write_str(&server_name_to_string(server_name)),
+            CertificateName::Wildcard(server_name) => {
+                write!(f, "*.{}", server_name_to_string(server_name))
+            }
+        }
+    }
+}
+
+/// Signature schemes used to check that a private key belongs to a certificate.
+const KEY_CHECK_SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Whether the private key of `certified_key` belongs to its end-entity
+/// certificate, checked by verifying a signature made with the key.
+fn key_matches_certificate(certified_key: &CertifiedKey) -> bool {
+    let message = b"RustStrom key check";
+    let Ok(certificate) = certified_key.end_entity_cert() else {
+        return false;
+    };
+    let Ok(certificate) = webpki::EndEntityCert::try_from(certificate.0.as_slice()) else {
+        return false;
+    };
+    let offered = KEY_CHECK_SCHEMES.map(|(scheme, _)| scheme);
+    let Some(signer) = certified_key.key.choose_scheme(&offered) else {
+        return false;
+    };
+    let Ok(signature) = signer.sign(message) else {
+        return false;
+    };
+    KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .is_some_and(|(_, algorithm)| {
+            certificate
+                .verify_signature(algorithm, message, &signature)
+                .is_ok()
+        })
 }
 
 fn load_certs<P>(path: P) -> io::Result<Vec<Certificate>>
@@ -116,40 +264,1261 @@ where
     ))
 }
 
+/// TLS settings for connections to the backend servers of a
+/// [`BackendPool`](crate::server::BackendPool).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamTls {
+    /// PEM file with the CAs to trust, the system's root store is used if absent
+    pub ca_bundle_path: Option<PathBuf>,
+    /// Sent as SNI and verified against the certificate instead of the address
+    pub server_name: Option<String>,
+    /// PEM files with the certificate chain and key presented for mutual TLS
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+    /// Accept any certificate, for development only
+    pub insecure_skip_verify: bool,
+}
+
+impl UpstreamTls {
+    pub fn client_config(&self) -> io::Result<ClientConfig> {
+        let root_store = if self.insecure_skip_verify {
+            RootCertStore::empty()
+        } else {
+            self.root_store()?
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store);
+        let mut config = match &self.client_certificate {
+            Some((certificate_path, private_key_path)) => {
+                let certificates = load_certs(certificate_path)?;
+                let private_key = load_key(private_key_path)?;
+                builder
+                    .with_client_auth_cert(certificates, private_key)
+                    .map_err(|e| {
+                        io::Error::new(
+                            InvalidData,
+                            format!(
+                                "Invalid client certificate '{}': {}",
+                                certificate_path.display(),
+                                e
+                            ),
+                        )
+                    })?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        if self.insecure_skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertificateVerification));
+        }
+        Ok(config)
+    }
+
+    fn root_store(&self) -> io::Result<RootCertStore> {
+        let certificates = match &self.ca_bundle_path {
+            Some(ca_bundle_path) => load_certs(ca_bundle_path)?,
+            None => rustls_native_certs::load_native_certs()?
+                .into_iter()
+                .map(|it| Certificate(it.0))
+                .collect(),
+        };
+        let mut root_store = RootCertStore::empty();
+        let (_, invalid) = root_store.add_parsable_certificates(
+            &certificates.into_iter().map(|it| it.0).collect::<Vec<_>>(),
+        );
+        if invalid > 0 {
+            warn!("Ignored {} invalid CA certificates", invalid);
+        }
+        Ok(root_store)
+    }
+}
+
+/// The TLS client config used by pools without [`UpstreamTls`]. It is never
+/// used to establish a connection, since their backends are contacted via HTTP.
+pub fn plain_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth()
+}
+
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The TLS versions, cipher suites and key exchange groups an HTTPS listener
+/// offers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPolicy {
+    pub versions: Vec<ProtocolVersion>,
+    pub cipher_suites: Vec<CipherSuite>,
+    pub kx_groups: Vec<NamedGroup>,
+}
+
+impl Default for TlsPolicy {
+    /// The safe defaults of rustls, TLS 1.2 and 1.3 with all their suites.
+    fn default() -> Self {
+        TlsPolicy {
+            versions: ALL_VERSIONS.iter().map(|it| it.version).collect(),
+            cipher_suites: ALL_CIPHER_SUITES.iter().map(|it| it.suite()).collect(),
+            kx_groups: ALL_KX_GROUPS.iter().map(|it| it.name).collect(),
+        }
+    }
+}
+
+impl TlsPolicy {
+    /// The policy restricted to the versions from `min_version` to
+    /// `max_version` (e.g. `"1.2"` or `"1.3"`) and the named `cipher_suites`
+    /// and `kx_groups`. Absent settings do not restrict the defaults.
+    pub fn new(
+        min_version: Option<&str>,
+        max_version: Option<&str>,
+        cipher_suites: Option<&[String]>,
+        kx_groups: Option<&[String]>,
+    ) -> io::Result<TlsPolicy> {
+        let defaults = TlsPolicy::default();
+        let min_version = min_version.map(parse_version).transpose()?;
+        let max_version = max_version.map(parse_version).transpose()?;
+        let versions = defaults
+            .versions
+            .into_iter()
+            .filter(|version| {
+                let version = version.get_u16();
+                min_version.is_none_or(|min| version >= min)
+                    && max_version.is_none_or(|max| version <= max)
+            })
+            .collect::<Vec<_>>();
+        if versions.is_empty() {
+            return Err(io::Error::new(
+                InvalidData,
+                "min_version is greater than max_version",
+            ));
+        }
+        let cipher_suites = match cipher_suites {
+            Some(names) => names
+                .iter()
+                .map(|name| find_by_name(name, &defaults.cipher_suites, "cipher suite"))
+                .collect::<Result<_, _>>()?,
+            None => defaults.cipher_suites,
+        };
+        let kx_groups = match kx_groups {
+            Some(names) => names
+                .iter()
+                .map(|name| find_by_name(name, &defaults.kx_groups, "key exchange group"))
+                .collect::<Result<_, _>>()?,
+            None => defaults.kx_groups,
+        };
+        let policy = TlsPolicy {
+            versions,
+            cipher_suites,
+            kx_groups,
+        };
+        for version in &policy.versions {
+            let usable = policy
+                .supported_cipher_suites()
+                .iter()
+                .any(|suite| suite.version().version == *version);
+            if !usable {
+                return Err(io::Error::new(
+                    InvalidData,
+                    format!(
+                        "None of the cipher suites can be used with {}",
+                        version_name(*version)
+                    ),
+                ));
+            }
+        }
+        policy
+            .server_config_builder()
+            .map_err(|e| io::Error::new(InvalidData, e.to_string()))?;
+        Ok(policy)
+    }
+
+    fn supported_cipher_suites(&self) -> Vec<SupportedCipherSuite> {
+        self.cipher_suites
+            .iter()
+            .filter_map(|suite| ALL_CIPHER_SUITES.iter().find(|it| it.suite() == *suite))
+            .copied()
+            .collect()
+    }
+
+    fn server_config_builder(
+        &self,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, rustls::Error> {
+        let kx_groups = self
+            .kx_groups
+            .iter()
+            .filter_map(|group| ALL_KX_GROUPS.iter().find(|it| it.name == *group))
+            .copied()
+            .collect::<Vec<_>>();
+        let versions = ALL_VERSIONS
+            .iter()
+            .filter(|it| self.versions.contains(&it.version))
+            .copied()
+            .collect::<Vec<_>>();
+        ServerConfig::builder()
+            .with_cipher_suites(&self.supported_cipher_suites())
+            .with_kx_groups(&kx_groups)
+            .with_protocol_versions(&versions)
+    }
+}
+
+impl fmt::Display for TlsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |names: Vec<String>| names.join(", ");
+        write!(
+            f,
+            "versions {}; cipher suites {}; key exchange groups {}",
+            names(self.versions.iter().map(|it| version_name(*it)).collect()),
+            names(
+                self.cipher_suites
+                    .iter()
+                    .map(|it| format!("{:?}", it))
+                    .collect()
+            ),
+            names(
+                self.kx_groups
+                    .iter()
+                    .map(|it| format!("{:?}", it))
+                    .collect()
+            )
+        )
+    }
+}
+
+/// The wire value of the TLS version `name`, e.g. `"1.3"`.
+fn parse_version(name: &str) -> io::Result<u16> {
+    TlsPolicy::default()
+        .versions
+        .into_iter()
+        .find(|version| version_name(*version) == format!("TLS {}", name))
+        .map(|version| version.get_u16())
+        .ok_or_else(|| {
+            io::Error::new(
+                InvalidData,
+                format!("Unsupported TLS version '{}', use 1.2 or 1.3", name),
+            )
+        })
+}
+
+fn version_name(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+        ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The element of `supported` whose debug representation is `name`, ignoring
+/// the case, e.g. `TLS13_AES_256_GCM_SHA384` or `X25519`.
+fn find_by_name<T: fmt::Debug + Copy>(name: &str, supported: &[T], kind: &str) -> io::Result<T> {
+    supported
+        .iter()
+        .find(|it| format!("{:?}", it).eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| {
+            io::Error::new(
+                InvalidData,
+                format!(
+                    "Unsupported {} '{}', supported are: {}",
+                    kind,
+                    name,
+                    supported
+                        .iter()
+                        .map(|it| format!("{:?}", it))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+        })
+}
+
+/// How clients of an HTTPS listener resume their sessions without a full
+/// handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionResumption {
+    /// Issue stateless session tickets.
+    pub tickets: bool,
+    /// The file with the ticket keys shared by RustStrom instances, `None`
+    /// uses random keys which are rotated every 6 hours.
+    pub ticket_key_path: Option<PathBuf>,
+    /// The number of sessions kept for resumption via session IDs or TLS 1.3
+    /// clients without tickets, 0 disables the cache.
+    pub cache_size: usize,
+}
+
+impl Default for SessionResumption {
+    fn default() -> Self {
+        SessionResumption {
+            tickets: true,
+            ticket_key_path: None,
+            cache_size: DEFAULT_SESSION_CACHE_SIZE,
+        }
+    }
+}
+
+/// The session cache size of rustls.
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+impl SessionResumption {
+    /// Applies the settings to `tls_config` of the listener, tickets issued
+    /// with shared keys are bound to `ticket_context`.
+    fn apply_to(&self, tls_config: &mut ServerConfig, ticket_context: Vec<u8>) {
+        tls_config.session_storage = match self.cache_size {
+            0 => Arc::new(NoServerSessionStorage {}),
+            size => ServerSessionMemoryCache::new(size),
+        };
+        if !self.tickets {
+            return;
+        }
+        let ticketer = match &self.ticket_key_path {
+            Some(path) => SharedTicketer::new(path.clone(), ticket_context)
+                .map(|it| Arc::new(it) as Arc<dyn ProducesTickets>),
+            None => Ticketer::new().map_err(|e| io::Error::other(e.to_string())),
+        };
+        match ticketer {
+            Ok(ticketer) => tls_config.ticketer = ticketer,
+            Err(e) => warn!("Session tickets are disabled: {}", e),
+        }
+    }
+}
+
+/// Client certificate authentication (mutual TLS) on an HTTPS listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    /// The CAs client certificates are verified against
+    pub ca_certificates: Vec<Certificate>,
+    /// Reject clients without a certificate instead of treating them as
+    /// anonymous
+    pub required: bool,
+}
+
+impl ClientAuth {
+    pub fn load<P: AsRef<Path>>(ca_bundle_path: P, required: bool) -> io::Result<ClientAuth> {
+        let ca_certificates = load_certs(&ca_bundle_path)?;
+        let invalid = || {
+            io::Error::new(
+                InvalidData,
+                format!(
+                    "Invalid CA certificates in '{}'",
+                    ca_bundle_path.as_ref().display()
+                ),
+            )
+        };
+        let mut root_store = RootCertStore::empty();
+        let (valid, invalid_count) = root_store.add_parsable_certificates(
+            &ca_certificates
+                .iter()
+                .map(|it| it.0.clone())
+                .collect::<Vec<_>>(),
+        );
+        if valid == 0 || invalid_count > 0 {
+            return Err(invalid());
+        }
+        Ok(ClientAuth {
+            ca_certificates,
+            required,
+        })
+    }
+
+    fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_parsable_certificates(
+            &self
+                .ca_certificates
+                .iter()
+                .map(|it| it.0.clone())
+                .collect::<Vec<_>>(),
+        );
+        if self.required {
+            AllowAnyAuthenticatedClient::new(root_store).boxed()
+        } else {
+            AllowAnyAnonymousOrAuthenticatedClient::new(root_store).boxed()
+        }
+    }
+}
+
+/// The verified certificate a client authenticated with, see [`ClientAuth`].
+/// Attached to requests as extension, so that middlewares can inspect it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The subject as RFC 4514 distinguished name, e.g. `CN=client,O=Partner`
+    pub subject: String,
+    /// The subject alternative names, e.g. `DNS:client.example.com`
+    pub alt_names: Vec<String>,
+    /// The SHA-256 fingerprint of the DER encoded certificate, hex encoded
+    pub fingerprint: String,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Option<ClientCertificate> {
+        let certificate = X509::from_der(der).ok()?;
+        // RFC 4514 lists the most specific attribute first
+        let subject = certificate
+            .subject_name()
+            .entries()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|entry| {
+                let name = entry.object().nid().short_name().unwrap_or("UNKNOWN");
+                let value = entry
+                    .data()
+                    .as_utf8()
+                    .map(|it| escape_dn_value(&it))
+                    .unwrap_or_default();
+                format!("{}={}", name, value)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let alt_names = certificate
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        if let Some(dns) = name.dnsname() {
+                            Some(format!("DNS:{}", dns))
+                        } else if let Some(email) = name.email() {
+                            Some(format!("email:{}", email))
+                        } else if let Some(uri) = name.uri() {
+                            Some(format!("URI:{}", uri))
+                        } else {
+                            name.ipaddress().and_then(ip_address_to_string)
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let fingerprint = hash(MessageDigest::sha256(), der)
+            .ok()?
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Some(ClientCertificate {
+            subject,
+            alt_names,
+            fingerprint,
+        })
+    }
+}
+
+/// Escapes the special characters of an attribute value, see RFC 4514
+/// section 2.4.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let leading = i == 0 && (c == ' ' || c == '#');
+        let trailing = i == last && c == ' ';
+        if leading || trailing || matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn ip_address_to_string(bytes: &[u8]) -> Option<String> {
+    let address = match bytes.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+        _ => return None,
+    };
+    Some(format!("IP:{}", address))
+}
+
+/// The request headers the [`ClientCertificate`] is passed to backends in.
+/// They are removed from all requests, so that clients can not spoof them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificateHeaders {
+    pub subject: HeaderName,
+    /// Comma separated
+    pub alt_names: HeaderName,
+    pub fingerprint: HeaderName,
+}
+
+impl Default for ClientCertificateHeaders {
+    fn default() -> Self {
+        ClientCertificateHeaders {
+            subject: HeaderName::from_static("x-client-cert-subject"),
+            alt_names: HeaderName::from_static("x-client-cert-sans"),
+            fingerprint: HeaderName::from_static("x-client-cert-fingerprint"),
+        }
+    }
+}
+
+impl ClientCertificateHeaders {
+    /// Replaces the headers sent by the client with the ones describing
+    /// `certificate`.
+    pub fn apply(&self, certificate: Option<&ClientCertificate>, headers: &mut HeaderMap) {
+        for name in [&self.subject, &self.alt_names, &self.fingerprint] {
+            headers.remove(name);
+        }
+        let Some(certificate) = certificate else {
+            return;
+        };
+        let values = [
+            (&self.subject, certificate.subject.clone()),
+            (&self.alt_names, certificate.alt_names.join(", ")),
+            (&self.fingerprint, certificate.fingerprint.clone()),
+        ];
+        for (name, value) in values {
+            // values with characters not allowed in headers are left out
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// The TLS config of the HTTPS `listener`. Its certificates are resolved from
+/// the currently loaded config, see [`ReconfigurableCertificateResolver`].
+pub fn server_config(config: Arc<ArcSwap<RuntimeConfig>>, listener: &Listener) -> ServerConfig {
+    let cert_resolver = ReconfigurableCertificateResolver::new(config, listener.name.clone());
+
+    let builder = listener
+        .tls_policy
+        .server_config_builder()
+        // validated when the config was loaded
+        .expect("Invalid TLS policy");
+    let builder = match &listener.client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(client_auth.verifier()),
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder.with_cert_resolver(Arc::new(cert_resolver));
+    tls_config.alpn_protocols = listener
+        .alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        // only chosen by ACME validation servers, which offer nothing else
+        .chain([ACME_TLS_ALPN_PROTOCOL.to_vec()])
+        .collect();
+    listener
+        .session_resumption
+        .apply_to(&mut tls_config, ticket_context(listener));
+    tls_config
+}
+
+/// Identifies the listener and its client certificate settings, which a
+/// resumed session skips.
+fn ticket_context(listener: &Listener) -> Vec<u8> {
+    let mut context = listener.name.as_bytes().to_vec();
+    if let Some(client_auth) = &listener.client_auth {
+        context.push(if client_auth.required { 2 } else { 1 });
+        for certificate in &client_auth.ca_certificates {
+            context.extend(openssl::sha::sha256(&certificate.0));
+        }
+    }
+    context
+}
+
+/// Resolves the certificate offered on the HTTPS listener named `listener`
+/// from the currently loaded [`RuntimeConfig`], so that certificates can be
+/// changed by reloading the config.
 pub struct ReconfigurableCertificateResolver<A>
 where
-    A: Access<HashMap<ServerName, CertifiedKey>>,
+    A: Access<RuntimeConfig>,
 {
-    certificates: A,
+    config: A,
+    listener: String,
 }
 
 impl<A> ReconfigurableCertificateResolver<A>
 where
-    A: Access<HashMap<ServerName, CertifiedKey>>,
+    A: Access<RuntimeConfig>,
 {
-    pub fn new(certificates: A) -> ReconfigurableCertificateResolver<A> {
-        ReconfigurableCertificateResolver { certificates }
+    pub fn new(config: A, listener: String) -> ReconfigurableCertificateResolver<A> {
+        ReconfigurableCertificateResolver { config, listener }
     }
 }
 
 impl<A> ResolvesServerCert for ReconfigurableCertificateResolver<A>
 where
-    A: Access<HashMap<ServerName, CertifiedKey>> + Send + Sync,
+    A: Access<RuntimeConfig> + Send + Sync,
 {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        if let Some(name) = client_hello.server_name() {
-            let certificates = self.certificates.load();
-            // Convert &str to ServerName to look up in our HashMap
-            if let Ok(server_name) = name.try_into() {
-                certificates
-                    .get(&server_name)
-                    .map(|ck| Arc::new(ck.clone()))
-            } else {
-                None
+        let config = self.config.load();
+        let mut alpn = client_hello.alpn().into_iter().flatten();
+        if alpn.any(|protocol| protocol == ACME_TLS_ALPN_PROTOCOL) {
+            // a TLS-ALPN-01 validation, answered on every listener
+            let name = client_hello.server_name()?;
+            return config.shared_data.acme_handler.tls_alpn_challenge(name);
+        }
+        let listener = config.listener(&self.listener)?;
+        let matched = client_hello
+            .server_name()
+            .and_then(|name| matching_certificate(&config, listener, name));
+        if let Some(certificate) = matched {
+            return Some(Arc::new(certificate.clone()));
+        }
+
+        let reason = match client_hello.server_name() {
+            Some(name) => {
+                debug!("No certificate for SNI '{}' on '{}'", name, self.listener);
+                "unknown_name"
             }
-        } else {
-            // This kind of resolver requires SNI
-            None
+            None => "no_sni",
+        };
+        metrics::TLS_UNMATCHED_SNI_TOTAL
+            .with_label_values(&[&self.listener, reason])
+            .inc();
+        config
+            .default_certificate
+            .as_ref()
+            .filter(|it| listener.offers(it))
+            .and_then(|it| config.certificates.get(it))
+            .map(|certificate| Arc::new(certificate.clone()))
+    }
+}
+
+/// The certificate offered on `listener` for the SNI `name`, an exact match
+/// is preferred over a wildcard one.
+fn matching_certificate<'a>(
+    config: &'a RuntimeConfig,
+    listener: &Listener,
+    name: &str,
+) -> Option<&'a CertifiedKey> {
+    CertificateName::candidates(name)
+        .into_iter()
+        .filter(|it| listener.offers(it))
+        .find_map(|it| config.certificates.get(&it))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        acme::AcmeHandler,
+        forwarded::TrustedProxies,
+        server::{Scheme, SharedData},
+        session_tickets::tests::write_ticket_keys,
+    };
+    use openssl::{
+        ec::EcGroup,
+        ec::EcKey,
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::extension::SubjectAlternativeName,
+    };
+    use std::{collections::HashMap, env, fs, process, time::Duration};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    /// Writes a self-signed certificate for `name` and its key to a new
+    /// directory, returns the paths of both files.
+    pub fn write_self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let (certificate, key) = self_signed(name);
+        write_pair(name, &certificate, &key)
+    }
+
+    /// Writes `certificate` and `key` to a new directory, returns the paths of
+    /// both files.
+    fn write_pair(name: &str, certificate: &[u8], key: &[u8]) -> (PathBuf, PathBuf) {
+        let directory = env::temp_dir().join(format!(
+            "rust-strom-{}-{}-{}",
+            process::id(),
+            name,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        let certificate_path = directory.join("cert.pem");
+        let private_key_path = directory.join("key.pem");
+        fs::write(&certificate_path, certificate).unwrap();
+        fs::write(&private_key_path, key).unwrap();
+        (certificate_path, private_key_path)
+    }
+
+    /// A self-signed certificate for `name` and its PKCS8 key, both PEM encoded.
+    pub fn self_signed(name: &str) -> (Vec<u8>, Vec<u8>) {
+        let (certificate, key) = issue(name, None);
+        (
+            certificate.to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// A certificate for the common name `name` signed by `issuer`, or
+    /// self-signed without one, and its key.
+    pub fn issue(name: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = openssl::x509::X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("O", "RustStrom Test").unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                builder.set_issuer_name(issuer.subject_name()).unwrap();
+                let alt_names = SubjectAlternativeName::new()
+                    .dns(name)
+                    .email("ops@example.com")
+                    .build(&builder.x509v3_context(Some(issuer), None))
+                    .unwrap();
+                builder.append_extension(alt_names).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    fn certificate_name(name: &str) -> CertificateName {
+        CertificateName::try_from(name).unwrap()
+    }
+
+    pub fn certified_key(name: &str) -> CertifiedKey {
+        let (certificate_path, private_key_path) = write_self_signed(name);
+        load_certified_key(certificate_path, private_key_path).unwrap()
+    }
+
+    pub fn runtime_config(
+        certificates: &[(&str, &CertifiedKey)],
+        listener_certificates: Option<&[&str]>,
+        default_certificate: Option<&str>,
+    ) -> RuntimeConfig {
+        RuntimeConfig {
+            listeners: vec![Listener {
+                name: "https".into(),
+                address: "127.0.0.1:0".parse().unwrap(),
+                scheme: Scheme::HTTPS,
+                certificates: listener_certificates
+                    .map(|names| names.iter().map(|it| certificate_name(it)).collect()),
+                proxy_protocol: None,
+                alpn_protocols: Vec::new(),
+                client_auth: None,
+                tls_policy: TlsPolicy::default(),
+                session_resumption: SessionResumption::default(),
+                handshake_timeout: Duration::from_secs(10),
+                max_concurrent_handshakes: 1,
+            }],
+            shared_data: SharedData {
+                backend_pools: Vec::new(),
+                trusted_proxies: TrustedProxies::default(),
+                acme_handler: Arc::new(AcmeHandler::new()),
+                client_certificate_headers: Default::default(),
+            },
+            certificates: certificates
+                .iter()
+                .map(|(name, key)| (certificate_name(name), (*key).clone()))
+                .collect(),
+            certificate_files: HashMap::new(),
+            acme_certificates: HashMap::new(),
+            ocsp_sources: HashMap::new(),
+            default_certificate: default_certificate.map(certificate_name),
+        }
+    }
+
+    /// The end-entity certificate the listener of `config` offers to a client
+    /// connecting to `server_name`, which only sends it as SNI if it is a DNS name.
+    async fn offered_certificate(config: RuntimeConfig, server_name: &str) -> Option<Certificate> {
+        let (offered, _) = handshake(config, server_name, None).await?;
+        Some(offered)
+    }
+
+    /// Connects to the listener of `config` as `server_name` with the optional
+    /// `client_certificate` files. Returns the certificate offered by the
+    /// listener and the client certificate it verified, `None` if the
+    /// handshake failed.
+    async fn handshake(
+        config: RuntimeConfig,
+        server_name: &str,
+        client_certificate: Option<(PathBuf, PathBuf)>,
+    ) -> Option<(Certificate, Option<ClientCertificate>)> {
+        let (stream, client_certificate) = connect(config, server_name, client_certificate).await?;
+        let (_, connection) = stream.get_ref();
+        let offered = connection.peer_certificates()?[0].clone();
+        Some((offered, client_certificate))
+    }
+
+    /// The TLS version and cipher suite negotiated with the first listener of
+    /// `config` by a client offering all supported ones.
+    async fn negotiated(config: RuntimeConfig) -> Option<(ProtocolVersion, CipherSuite)> {
+        let (stream, _) = connect(config, "localhost", None).await?;
+        let (_, connection) = stream.get_ref();
+        Some((
+            connection.protocol_version()?,
+            connection.negotiated_cipher_suite()?.suite(),
+        ))
+    }
+
+    /// A new acceptor for the first listener of `config`, with its own session
+    /// cache and ticket keys.
+    fn acceptor(config: &RuntimeConfig) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::new(server_config(
+            Arc::new(ArcSwap::from_pointee(config.clone())),
+            &config.listeners[0],
+        )))
+    }
+
+    /// A client that remembers its sessions for resumption.
+    fn resuming_client() -> TlsConnector {
+        let client_config = UpstreamTls {
+            ca_bundle_path: None,
+            server_name: None,
+            client_certificate: None,
+            insecure_skip_verify: true,
+        }
+        .client_config()
+        .unwrap();
+        TlsConnector::from(Arc::new(client_config))
+    }
+
+    /// Whether `connector` resumed a session with `acceptor`.
+    async fn resumed(acceptor: &TlsAcceptor, connector: &TlsConnector) -> bool {
+        try_resume(acceptor, connector).await.unwrap()
+    }
+
+    async fn try_resume(acceptor: &TlsAcceptor, connector: &TlsConnector) -> io::Result<bool> {
+        let (client, server) = duplex(16 * 1024);
+        let (connected, accepted) = tokio::join!(
+            async {
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let mut stream = connector.connect(server_name, client).await?;
+                // TLS 1.3 tickets are sent after the handshake
+                stream.read_exact(&mut [0; 1]).await
+            },
+            async {
+                // only available again if the session is resumed
+                let mut stream = acceptor
+                    .accept_with(server, |it| it.set_resumption_data(b"resumed"))
+                    .await?;
+                stream.write_all(b"x").await?;
+                stream.flush().await?;
+                let (_, connection) = stream.get_ref();
+                Ok::<_, io::Error>(connection.received_resumption_data().is_some())
+            }
+        );
+        connected?;
+        accepted
+    }
+
+    async fn connect(
+        config: RuntimeConfig,
+        server_name: &str,
+        client_certificate: Option<(PathBuf, PathBuf)>,
+    ) -> Option<(
+        tokio_rustls::client::TlsStream<DuplexStream>,
+        Option<ClientCertificate>,
+    )> {
+        let listener = config.listeners[0].clone();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(
+            Arc::new(ArcSwap::from_pointee(config)),
+            &listener,
+        )));
+        let client_config = UpstreamTls {
+            ca_bundle_path: None,
+            server_name: None,
+            client_certificate,
+            insecure_skip_verify: true,
         }
+        .client_config()
+        .unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let (client, server) = duplex(16 * 1024);
+        let server_name = ServerName::try_from(server_name).unwrap();
+        let (connected, accepted) = tokio::join!(
+            async move {
+                let mut stream = connector.connect(server_name, client).await?;
+                // TLS 1.3 servers reject client certificates after the client finished
+                stream.write_all(b"x").await?;
+                stream.flush().await?;
+                Ok::<_, io::Error>(stream)
+            },
+            async move {
+                let mut stream = acceptor.accept(server).await?;
+                stream.read_exact(&mut [0; 1]).await?;
+                let (_, connection) = stream.get_ref();
+                Ok::<_, io::Error>(
+                    connection
+                        .peer_certificates()
+                        .and_then(|it| ClientCertificate::from_der(&it[0].0)),
+                )
+            }
+        );
+        Some((connected.ok()?, accepted.ok()?))
+    }
+
+    /// A listener config with a certificate for `localhost`, which verifies
+    /// client certificates against a new CA. Returns the config and the files
+    /// of a client certificate issued by the CA for `client.example.com`.
+    pub fn client_auth_config(required: bool) -> (RuntimeConfig, (PathBuf, PathBuf)) {
+        let (ca, ca_key) = issue("RustStrom Test CA", None);
+        let (ca_path, _) = write_pair(
+            "ca",
+            &ca.to_pem().unwrap(),
+            &ca_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let (client, client_key) = issue("client.example.com", Some((&ca, &ca_key)));
+        let client_files = write_pair(
+            "client",
+            &client.to_pem().unwrap(),
+            &client_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let server = certified_key("localhost");
+        let mut config = runtime_config(&[("localhost", &server)], None, None);
+        config.listeners[0].client_auth = Some(ClientAuth::load(ca_path, required).unwrap());
+        (config, client_files)
+    }
+
+    #[test]
+    fn parses_wildcard_certificate_names() {
+        assert_eq!(
+            certificate_name("*.Example.com"),
+            CertificateName::Wildcard("example.com".try_into().unwrap())
+        );
+        assert_eq!(
+            certificate_name("*.example.com").to_string(),
+            "*.example.com"
+        );
+        assert!(CertificateName::try_from("*.*.example.com").is_err());
+        assert!(CertificateName::try_from("api.*.example.com").is_err());
+    }
+
+    #[test]
+    fn prefers_exact_names_over_wildcards() {
+        let wildcard = certified_key("wildcard.example.com");
+        let exact = certified_key("api.example.com");
+        let config = runtime_config(
+            &[("*.example.com", &wildcard), ("api.example.com", &exact)],
+            None,
+            None,
+        );
+        let listener = &config.listeners[0];
+
+        let matched = |name| matching_certificate(&config, listener, name).map(|it| &it.cert);
+        assert_eq!(matched("api.example.com"), Some(&exact.cert));
+        assert_eq!(matched("WWW.example.com"), Some(&wildcard.cert));
+        // a wildcard covers a single label only
+        assert_eq!(matched("example.com"), None);
+        assert_eq!(matched("v1.api.example.com"), None);
+    }
+
+    #[test]
+    fn matches_wildcards_offered_on_the_listener_only() {
+        let wildcard = certified_key("wildcard.example.com");
+        let config = runtime_config(
+            &[("*.example.com", &wildcard)],
+            Some(&["*.example.com"]),
+            None,
+        );
+        assert!(matching_certificate(&config, &config.listeners[0], "www.example.com").is_some());
+
+        let config = runtime_config(&[("*.example.com", &wildcard)], Some(&[]), None);
+        assert!(matching_certificate(&config, &config.listeners[0], "www.example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn offers_the_default_certificate_without_matching_sni() {
+        let default = certified_key("default.example.com");
+        let other = certified_key("example.org");
+        let certificates = [("default.example.com", &default), ("example.org", &other)];
+
+        let config = runtime_config(&certificates, None, Some("default.example.com"));
+        assert_eq!(
+            offered_certificate(config, "127.0.0.1").await.as_ref(),
+            Some(&default.cert[0])
+        );
+        let config = runtime_config(&certificates, None, Some("default.example.com"));
+        assert_eq!(
+            offered_certificate(config, "unknown.example.net")
+                .await
+                .as_ref(),
+            Some(&default.cert[0])
+        );
+        let config = runtime_config(&certificates, None, Some("default.example.com"));
+        assert_eq!(
+            offered_certificate(config, "example.org").await.as_ref(),
+            Some(&other.cert[0])
+        );
+
+        let config = runtime_config(&certificates, None, None);
+        assert_eq!(offered_certificate(config, "127.0.0.1").await, None);
+    }
+
+    #[tokio::test]
+    async fn verifies_required_client_certificates() {
+        let (config, client_files) = client_auth_config(true);
+        let (_, client_certificate) = handshake(config, "localhost", Some(client_files))
+            .await
+            .unwrap();
+
+        let client_certificate = client_certificate.unwrap();
+        assert_eq!(
+            client_certificate.subject,
+            "CN=client.example.com,O=RustStrom Test"
+        );
+        assert_eq!(
+            client_certificate.alt_names,
+            vec!["DNS:client.example.com", "email:ops@example.com"]
+        );
+        assert_eq!(client_certificate.fingerprint.len(), 64);
+
+        let (config, _) = client_auth_config(true);
+        assert!(handshake(config, "localhost", None).await.is_none());
+        // a certificate of another CA
+        let (config, _) = client_auth_config(true);
+        assert!(handshake(
+            config,
+            "localhost",
+            Some(write_self_signed("client.example.com"))
+        )
+        .await
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn accepts_anonymous_clients_if_certificates_are_optional() {
+        let (config, client_files) = client_auth_config(false);
+        let (_, client_certificate) = handshake(config, "localhost", Some(client_files))
+            .await
+            .unwrap();
+        assert!(client_certificate.is_some());
+
+        let (config, _) = client_auth_config(false);
+        let (_, client_certificate) = handshake(config, "localhost", None).await.unwrap();
+        assert_eq!(client_certificate, None);
+    }
+
+    #[test]
+    fn replaces_client_certificate_headers_sent_by_the_client() {
+        let headers = ClientCertificateHeaders::default();
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            "x-client-cert-subject",
+            HeaderValue::from_static("CN=spoofed"),
+        );
+        request_headers.insert(
+            "x-client-cert-sans",
+            HeaderValue::from_static("DNS:spoofed"),
+        );
+
+        headers.apply(None, &mut request_headers);
+        assert!(request_headers.is_empty());
+
+        let certificate = ClientCertificate {
+            subject: "CN=client\\, Inc.".to_string(),
+            alt_names: vec!["DNS:a.example.com".into(), "IP:10.0.0.1".into()],
+            fingerprint: "ab01".to_string(),
+        };
+        headers.apply(Some(&certificate), &mut request_headers);
+        assert_eq!(
+            request_headers["x-client-cert-subject"],
+            "CN=client\\, Inc."
+        );
+        assert_eq!(
+            request_headers["x-client-cert-sans"],
+            "DNS:a.example.com, IP:10.0.0.1"
+        );
+        assert_eq!(request_headers["x-client-cert-fingerprint"], "ab01");
+    }
+
+    fn policy(
+        min_version: Option<&str>,
+        max_version: Option<&str>,
+        cipher_suites: Option<&[&str]>,
+        kx_groups: Option<&[&str]>,
+    ) -> io::Result<TlsPolicy> {
+        let names = |names: &[&str]| names.iter().map(|it| it.to_string()).collect::<Vec<_>>();
+        TlsPolicy::new(
+            min_version,
+            max_version,
+            cipher_suites.map(names).as_deref(),
+            kx_groups.map(names).as_deref(),
+        )
+    }
+
+    #[test]
+    fn restricts_the_tls_policy() {
+        assert_eq!(
+            policy(None, None, None, None).unwrap(),
+            TlsPolicy::default()
+        );
+        let restricted = policy(
+            Some("1.3"),
+            None,
+            Some(&["tls13_chacha20_poly1305_sha256", "TLS13_AES_256_GCM_SHA384"]),
+            Some(&["x25519"]),
+        )
+        .unwrap();
+        assert_eq!(
+            restricted,
+            TlsPolicy {
+                versions: vec![ProtocolVersion::TLSv1_3],
+                cipher_suites: vec![
+                    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                    CipherSuite::TLS13_AES_256_GCM_SHA384
+                ],
+                kx_groups: vec![NamedGroup::X25519],
+            }
+        );
+        assert_eq!(
+            restricted.to_string(),
+            "versions TLS 1.3; cipher suites TLS13_CHACHA20_POLY1305_SHA256, \
+             TLS13_AES_256_GCM_SHA384; key exchange groups X25519"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tls_policies() {
+        assert!(policy(Some("1.1"), None, None, None).is_err());
+        assert!(policy(Some("1.3"), Some("1.2"), None, None).is_err());
+        assert!(policy(None, None, Some(&["TLS_RSA_WITH_RC4_128_MD5"]), None).is_err());
+        assert!(policy(None, None, None, Some(&["ffdhe2048"])).is_err());
+        assert!(policy(None, None, None, Some(&[])).is_err());
+        assert!(policy(None, None, Some(&[]), None).is_err());
+        // TLS 1.2 would be offered without a cipher suite
+        assert!(policy(None, None, Some(&["TLS13_AES_128_GCM_SHA256"]), None).is_err());
+        assert!(policy(Some("1.3"), None, Some(&["TLS13_AES_128_GCM_SHA256"]), None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn negotiates_within_the_tls_policy() {
+        let certified_key = certified_key("localhost");
+        let mut config = runtime_config(&[("localhost", &certified_key)], None, None);
+        assert_eq!(
+            negotiated(config.clone()).await,
+            Some((
+                ProtocolVersion::TLSv1_3,
+                CipherSuite::TLS13_AES_256_GCM_SHA384
+            ))
+        );
+
+        config.listeners[0].tls_policy = policy(
+            None,
+            Some("1.2"),
+            Some(&["TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256"]),
+            Some(&["secp384r1"]),
+        )
+        .unwrap();
+        assert_eq!(
+            negotiated(config).await,
+            Some((
+                ProtocolVersion::TLSv1_2,
+                CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_sessions_with_tickets() {
+        let certified_key = certified_key("localhost");
+        let mut config = runtime_config(&[("localhost", &certified_key)], None, None);
+        config.listeners[0].session_resumption.cache_size = 0;
+        let first = acceptor(&config);
+        let client = resuming_client();
+        assert!(!resumed(&first, &client).await);
+        assert!(resumed(&first, &client).await);
+        // another instance with its own random keys
+        assert!(!resumed(&acceptor(&config), &client).await);
+
+        config.listeners[0].session_resumption.ticket_key_path =
+            Some(write_ticket_keys("fleet", &[[7; 32]]));
+        let client = resuming_client();
+        assert!(!resumed(&acceptor(&config), &client).await);
+        assert!(resumed(&acceptor(&config), &client).await);
+
+        config.listeners[0].session_resumption.tickets = false;
+        let no_resumption = acceptor(&config);
+        let client = resuming_client();
+        assert!(!resumed(&no_resumption, &client).await);
+        assert!(!resumed(&no_resumption, &client).await);
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_shared_tickets_without_client_auth() {
+        let (mut mtls, _) = client_auth_config(true);
+        mtls.listeners[0].session_resumption.ticket_key_path =
+            Some(write_ticket_keys("mtls", &[[8; 32]]));
+        let mut public = mtls.clone();
+        public.listeners[0].client_auth = None;
+        let client = resuming_client();
+        assert!(!resumed(&acceptor(&public), &client).await);
+        assert!(resumed(&acceptor(&public), &client).await);
+
+        // the client has no certificate, so it must not get in at all
+        assert!(try_resume(&acceptor(&mtls), &client).await.is_err());
+
+        // tickets issued before a CA rotation are not accepted either
+        let (rotated, _) = client_auth_config(false);
+        let mut optional = mtls.clone();
+        optional.listeners[0].client_auth = rotated.listeners[0].client_auth.clone();
+        let client = resuming_client();
+        assert!(!resumed(&acceptor(&optional), &client).await);
+        assert!(resumed(&acceptor(&optional), &client).await);
+        let mut previous_ca = optional.clone();
+        previous_ca.listeners[0].client_auth = mtls.listeners[0].client_auth.clone();
+        previous_ca.listeners[0]
+            .client_auth
+            .as_mut()
+            .unwrap()
+            .required = false;
+        assert!(!resumed(&acceptor(&previous_ca), &client).await);
+    }
+
+    #[tokio::test]
+    async fn resumes_sessions_from_the_cache() {
+        let certified_key = certified_key("localhost");
+        let mut config = runtime_config(&[("localhost", &certified_key)], None, None);
+        // TLS 1.3 tickets are then only the key of the cached session
+        config.listeners[0].session_resumption.tickets = false;
+        let acceptor = acceptor(&config);
+        let client = resuming_client();
+        assert!(!resumed(&acceptor, &client).await);
+        assert!(resumed(&acceptor, &client).await);
+    }
+
+    #[test]
+    fn counts_the_days_until_expiry() {
+        let (certificate_path, private_key_path) = write_self_signed("example.com");
+        let certified_key = load_certified_key(certificate_path, private_key_path).unwrap();
+
+        // the certificate expires in 30 days, minus the seconds since it was created
+        assert!(matches!(valid_days_left(&certified_key), Some(29..=30)));
+    }
+
+    #[test]
+    fn loads_matching_certificate_and_key() {
+        let (certificate_path, private_key_path) = write_self_signed("example.com");
+
+        assert!(load_certified_key(certificate_path, private_key_path).is_ok());
+    }
+
+    #[test]
+    fn rejects_key_of_another_certificate() {
+        let (certificate_path, _) = write_self_signed("example.com");
+        let (_, other_key_path) = write_self_signed("example.org");
+
+        let result = load_certified_key(certificate_path, other_key_path);
+        assert!(matches!(result, Err(e) if e.kind() == InvalidData));
     }
 }
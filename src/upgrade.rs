@@ -0,0 +1,165 @@
+use crate::{metrics, middleware::is_upgrade, timeouts::TimeoutKind};
+use hyper::{upgrade::OnUpgrade, Body, Request, Response, StatusCode};
+use log::{debug, warn};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+/// Returns whether the client asks to switch protocols, e.g. to WebSocket,
+/// i.e. `Connection` contains the `upgrade` option and `Upgrade` is present.
+pub fn is_upgrade_request(request: &Request<Body>) -> bool {
+    is_upgrade(request.headers())
+}
+
+/// Bridges the upgraded client connection with the upgraded backend connection
+/// once the backend agreed to switch protocols in `response`. The `response` is
+/// returned unchanged and must be sent to the client for its upgrade to finish.
+///
+/// The connections are closed once neither side sent any data for
+/// `idle_timeout`.
+pub fn bridge(
+    client_upgrade: OnUpgrade,
+    mut response: Response<Body>,
+    backend_address: String,
+    idle_timeout: Option<Duration>,
+) -> Response<Body> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
+    let backend_upgrade = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        let (client, backend) = match tokio::try_join!(client_upgrade, backend_upgrade) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("Upgrade of connection to {} failed: {}", backend_address, e);
+                return;
+            }
+        };
+        metrics::ACTIVE_UPGRADED_CONNECTIONS.inc();
+        match splice(client, backend, idle_timeout).await {
+            Ok(()) => debug!("Upgraded connection to {} closed", backend_address),
+            Err(e) => debug!("Upgraded connection to {} aborted: {}", backend_address, e),
+        }
+        metrics::ACTIVE_UPGRADED_CONNECTIONS.dec();
+    });
+    response
+}
+
+/// Copies data between `client` and `backend` in both directions until both
+/// sides closed their write half, or nothing was received or could be passed
+/// on for `idle_timeout`.
+async fn splice<C, B>(
+    mut client: C,
+    mut backend: B,
+    idle_timeout: Option<Duration>,
+) -> Result<(), io::Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_buffer = vec![0; 8 * 1024];
+    let mut backend_buffer = vec![0; 8 * 1024];
+    let mut client_open = true;
+    let mut backend_open = true;
+
+    while client_open || backend_open {
+        // a peer which stops reading blocks the write, which counts as idle too
+        let step = async {
+            let (from_client, read) = tokio::select! {
+                read = client.read(&mut client_buffer), if client_open => (true, read),
+                read = backend.read(&mut backend_buffer), if backend_open => (false, read),
+            };
+            match (from_client, read?) {
+                (true, 0) => {
+                    client_open = false;
+                    backend.shutdown().await
+                }
+                (true, n) => backend.write_all(&client_buffer[..n]).await,
+                (false, 0) => {
+                    backend_open = false;
+                    client.shutdown().await
+                }
+                (false, n) => client.write_all(&backend_buffer[..n]).await,
+            }
+        };
+        match idle_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, step).await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} exceeded", TimeoutKind::UpgradeIdle),
+                )
+            })??,
+            None => step.await?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{CONNECTION, UPGRADE};
+    use tokio::io::duplex;
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let request = |connection: &str| {
+            Request::builder()
+                .header(CONNECTION, connection)
+                .header(UPGRADE, "websocket")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(is_upgrade_request(&request("Upgrade")));
+        assert!(is_upgrade_request(&request("keep-alive, upgrade")));
+        assert!(!is_upgrade_request(&request("keep-alive")));
+        assert!(!is_upgrade_request(&Request::new(Body::empty())));
+    }
+
+    #[tokio::test]
+    async fn splice_copies_both_directions() {
+        let (client, mut client_peer) = duplex(64);
+        let (backend, mut backend_peer) = duplex(64);
+        let spliced = tokio::spawn(splice(client, backend, None));
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 4];
+        backend_peer.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        backend_peer.write_all(b"pong").await.unwrap();
+        client_peer.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"pong");
+
+        drop(client_peer);
+        drop(backend_peer);
+        assert!(spliced.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn splice_closes_idle_connections() {
+        let (client, _client_peer) = duplex(64);
+        let (backend, _backend_peer) = duplex(64);
+
+        let result = splice(client, backend, Some(Duration::from_millis(10))).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn splice_closes_connections_to_peers_which_stopped_reading() {
+        let (client, mut client_peer) = duplex(64);
+        // the backend peer never reads, so writes to it block once 64 bytes are buffered
+        let (backend, _backend_peer) = duplex(64);
+        let spliced = tokio::spawn(splice(client, backend, Some(Duration::from_millis(50))));
+
+        // fails once the spliced connection is closed
+        let _ = client_peer.write_all(&[0; 1024]).await;
+
+        let result = timeout(Duration::from_secs(5), spliced).await.unwrap();
+        assert_eq!(result.unwrap().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}